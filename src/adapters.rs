@@ -0,0 +1,187 @@
+use std::sync::Mutex;
+use rayon::prelude::*;
+
+use crate::{sequentialbucketqueue::HasKey, ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
+
+/// Turns any sequential queue into a parallel one by putting it behind a lock. bulk_push takes the
+/// lock once per element and bulk_pop hands out everything in the first `bucketwidth` wide bucket,
+/// so a Locked BinaryHeap pops the same buckets a ParBqueue of that width would.
+#[derive(Debug)]
+pub struct Locked<Q> {
+    bucketwidth: f64,
+    queue: Mutex<Q>,
+}
+
+#[allow(dead_code)]
+impl<Q> Locked<Q> {
+    pub fn new(queue: Q, bucketwidth: f64) -> Self {
+        Locked { bucketwidth, queue: Mutex::new(queue) }
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.queue.into_inner().unwrap()
+    }
+}
+
+impl<'a, E: Ord + HasKey + 'a, Q: SeqentialPriorityQueue<'a, E>> SeqentialPriorityQueue<'a, E> for Locked<Q> {
+    fn push(&mut self, e: &'a E) {
+        self.queue.get_mut().unwrap().push(e)
+    }
+    fn pop(&mut self) -> Option<&'a E> {
+        self.queue.get_mut().unwrap().pop()
+    }
+    fn peek(&self) -> Option<&'a E> {
+        self.queue.lock().unwrap().peek()
+    }
+    fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+    fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+    fn clear(&mut self) {
+        self.queue.get_mut().unwrap().clear()
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        self.queue.get_mut().unwrap().drain_until(time)
+    }
+    fn reserve(&mut self, additional: usize) {
+        self.queue.get_mut().unwrap().reserve(additional)
+    }
+    /// Wrap bucketed queues with their own width, a Locked only adds buckets to queues that have none.
+    fn pop_order(&self) -> PopOrder {
+        match self.queue.lock().unwrap().pop_order() {
+            PopOrder::Exact => PopOrder::Bucketed { width: self.bucketwidth, sorted: true },
+            order => order,
+        }
+    }
+}
+
+impl<'a, E: Ord + HasKey + Send + Sync + 'a, Q: SeqentialPriorityQueue<'a, E> + Send> ParallelPriorityQueue<'a, E> for Locked<Q> {
+    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
+        let bucket = self.bulk_pop();
+        let mapped: Vec<&'a E> = bucket.filter_map(f).collect();
+        self.bulk_push(mapped.into_par_iter());
+    }
+
+    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
+        es.for_each(|e| {
+            self.queue.lock().unwrap().push(e);
+        });
+    }
+
+    /// Pops for as long as the next element is in the first one's bucket, worked out the way Bqueue
+    /// puts elements in buckets. Draining up to a computed end of the bucket is not the same: for a
+    /// key right on an edge, (floor(key/width)+1)*width can round to the key itself or below it.
+    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
+        let queue = self.queue.get_mut().unwrap();
+        let width = self.bucketwidth;
+        let bucket = |e: &E| (e.key().0/width).floor();
+        let mut ret = Vec::new();
+        if let Some(first) = queue.peek().map(bucket) {
+            while queue.peek().is_some_and(|e| bucket(e) == first) {
+                ret.push(queue.pop().unwrap());
+            }
+        }
+        ret.into_par_iter()
+    }
+}
+
+/// Hides the bulk operations of a parallel queue, so time_seqential and anything else written against
+/// SeqentialPriorityQueue drive it strictly one element at a time.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Sequential<PQ>(pub PQ);
+
+impl<'a, E: Ord + HasKey + Send + Sync + 'a, PQ: ParallelPriorityQueue<'a, E>> SeqentialPriorityQueue<'a, E> for Sequential<PQ> {
+    fn push(&mut self, e: &'a E) {
+        self.0.push(e)
+    }
+    fn pop(&mut self) -> Option<&'a E> {
+        self.0.pop()
+    }
+    fn peek(&self) -> Option<&'a E> {
+        self.0.peek()
+    }
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+    fn clear(&mut self) {
+        self.0.clear()
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        self.0.drain_until(time)
+    }
+    fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional)
+    }
+    fn pop_order(&self) -> PopOrder {
+        self.0.pop_order()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+    use ordered_float::OrderedFloat;
+
+    use crate::parallelbucketqueue::ParBqueue;
+    use crate::sequentialbucketqueue::Bqueue;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Ev(OrderedFloat<f64>);
+
+    impl HasKey for Ev {
+        fn key(&self) -> OrderedFloat<f64> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn locked_pops_buckets() {
+        let values: Vec<Ev> = (0..100).map(|i| Ev(OrderedFloat((i * 37 % 100) as f64 * 0.25))).collect();
+        let mut sorted: Vec<&Ev> = values.iter().collect();
+        sorted.sort();
+
+        let mut heap: Locked<BinaryHeap<Reverse<&Ev>>> = Locked::new(BinaryHeap::new(), 1.0);
+        heap.bulk_push(values.par_iter());
+        let mut bucket: Vec<&Ev> = heap.bulk_pop().collect();
+        bucket.sort();
+        assert_eq!(bucket, sorted[..4]);
+        assert_eq!(heap.len(), 96);
+        assert_eq!(heap.pop(), Some(sorted[4]));
+
+        let mut bq: Locked<Bqueue<&Ev>> = Locked::new(Bqueue::new(30, 1.0), 1.0);
+        bq.bulk_push(values.par_iter());
+        let mut count = 0;
+        while !bq.is_empty() {
+            let bucket: Vec<&Ev> = bq.bulk_pop().collect();
+            assert_eq!(bucket.len(), 4);
+            count += bucket.len();
+        }
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn sequential_pops_one_at_a_time() {
+        let values: Vec<Ev> = (0..50).map(|i| Ev(OrderedFloat(i as f64 * 0.5))).collect();
+        let mut heap = Sequential(ParBqueue::new(30, 1.0));
+        for v in values.iter().rev() {
+            heap.push(v);
+        }
+        assert_eq!(heap.len(), 50);
+        for v in values.chunks(2) {
+            let a = heap.pop().unwrap();
+            let b = heap.pop().unwrap();
+            assert!(v.contains(a) && v.contains(b));
+        }
+        assert!(heap.is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use ordered_float::OrderedFloat;
+
+use crate::{csvreader::Rec, particle::{Pair, Particle, ParticleIndex}, sequentialbucketqueue::HasKey, vectors::Vector};
+
+/// Index of an event in an EventArena.
+pub type Handle = u32;
+
+/// `next` of the last event of a pair in its step.
+pub const NO_EVENT: Handle = Handle::MAX;
+
+/// Everything a queue needs to order an arena event, 32 bytes instead of a whole KeyVal. Ties on
+/// the key go by pair and then by where the event sits in its step, the same as for KeyVal, so
+/// arena runs process events in the same order as the others. The handle never gets to break a tie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeyHandle {
+    pub key: OrderedFloat<f64>,
+    pub pair: Pair,
+    pub index: u32, // KeyVal::index
+    pub handle: Handle,
+}
+
+impl HasKey for KeyHandle {
+    fn key(&self) -> OrderedFloat<f64> {
+        self.key
+    }
+}
+
+// one particle of every event, column by column
+#[derive(Debug, Default)]
+struct Side {
+    id: Vec<ParticleIndex>,
+    pos: Vec<Vector>,
+    vel: Vec<Vector>,
+    r: Vec<f64>,
+    m: Vec<f64>,
+}
+
+impl Side {
+    fn push(&mut self, id: ParticleIndex, p: Particle) {
+        self.id.push(id);
+        self.pos.push(p.p);
+        self.vel.push(p.v);
+        self.r.push(p.r);
+        self.m.push(p.m);
+    }
+
+    fn particle(&self, h: Handle, t: f64) -> Particle {
+        let h = h as usize;
+        Particle { p: self.pos[h], v: self.vel[h], r: self.r[h], m: self.m[h], t }
+    }
+}
+
+/// Event storage in structure-of-arrays form. The queues only ever see the compact `keys` column,
+/// everything else is looked up by handle once an event gets processed.
+///
+/// Every event also knows the handle of the same pair's next event in its step, so a driver can
+/// push only the first event of each pair and chain on from there, instead of scanning the rest of
+/// the step and leaving it to a UniqueQueue to throw out the duplicates.
+#[derive(Debug, Default)]
+pub struct EventArena {
+    keys: Vec<KeyHandle>,
+    time: Vec<f64>,
+    p1: Side,
+    p2: Side,
+    next: Vec<Handle>,
+    heads: Vec<Vec<Handle>>, // per step, the first event of every pair in it
+}
+
+impl EventArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the arena from the events of each global step, in file order within a step.
+    pub fn from_steps<'r, S, I>(steps: S) -> Self where S: IntoIterator<Item = I>, I: IntoIterator<Item = &'r Rec> {
+        let mut arena = Self::new();
+        for step in steps {
+            arena.push_step(step);
+        }
+        arena
+    }
+
+    /// Appends the events of the next step.
+    pub fn push_step<'r>(&mut self, recs: impl IntoIterator<Item = &'r Rec>) {
+        let mut last: HashMap<Pair, Handle> = HashMap::new();
+        let mut heads = Vec::new();
+        for (index, rec) in recs.into_iter().enumerate() {
+            let h = self.keys.len() as Handle;
+            assert!(h != NO_EVENT, "more events than an arena can hand out handles for");
+            self.keys.push(KeyHandle { key: OrderedFloat(rec.time), pair: rec.pair(), index: index as u32, handle: h });
+            self.time.push(rec.time);
+            self.p1.push(rec.p1, rec.p1());
+            self.p2.push(rec.p2, rec.p2());
+            self.next.push(NO_EVENT);
+            match last.insert(rec.pair(), h) {
+                Some(prev) => self.next[prev as usize] = h,
+                None => heads.push(h),
+            }
+        }
+        self.heads.push(heads);
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn steps(&self) -> usize {
+        self.heads.len()
+    }
+
+    /// First event of every pair in `step`.
+    pub fn heads(&self, step: usize) -> &[Handle] {
+        &self.heads[step]
+    }
+
+    /// What goes into the queues for `h`.
+    pub fn key(&self, h: Handle) -> &KeyHandle {
+        &self.keys[h as usize]
+    }
+
+    /// The same pair's next event in the step, as something to push.
+    pub fn next_key(&self, h: Handle) -> Option<&KeyHandle> {
+        match self.next[h as usize] {
+            NO_EVENT => None,
+            next => Some(self.key(next)),
+        }
+    }
+
+    pub fn time(&self, h: Handle) -> f64 {
+        self.time[h as usize]
+    }
+
+    pub fn pair(&self, h: Handle) -> Pair {
+        Pair::new(self.p1.id[h as usize], self.p2.id[h as usize])
+    }
+
+    /// The first particle as it is at the event, like Rec::p1.
+    pub fn p1(&self, h: Handle) -> Particle {
+        self.p1.particle(h, self.time(h))
+    }
+
+    pub fn p2(&self, h: Handle) -> Particle {
+        self.p2.particle(h, self.time(h))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rayon::prelude::*;
+
+    use crate::parallelbucketqueue::ParBqueue;
+
+    use super::*;
+
+    fn rec(p1: u32, p2: u32, time: f64) -> Rec {
+        let a = Particle::new(Vector::new(p1 as f64, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 0.5, 1.0);
+        let b = Particle::new(Vector::new(p2 as f64, 0.0, 0.0), Vector::new(0.0, -1.0, 0.0), 0.25, 1.0);
+        Rec::new(ParticleIndex(p1 as usize), ParticleIndex(p2 as usize), &a, &b, time)
+    }
+
+    #[test]
+    fn chains_pairs_within_a_step() {
+        let steps = [
+            vec![rec(1, 2, 0.5), rec(3, 4, 0.1), rec(2, 1, 0.7), rec(1, 2, 0.9)],
+            vec![rec(1, 2, 1.5), rec(4, 3, 1.2)],
+        ];
+        let arena = EventArena::from_steps(steps.iter());
+        assert_eq!(arena.len(), 6);
+        assert_eq!(arena.steps(), 2);
+        assert_eq!(arena.heads(0), &[0, 1]);
+        assert_eq!(arena.heads(1), &[4, 5]);
+        assert_eq!(arena.next_key(0), Some(&KeyHandle { key: OrderedFloat(0.7), pair: steps[0][0].pair(), index: 2, handle: 2 }));
+        assert_eq!(arena.key(5).index, 1);
+        assert_eq!(arena.next_key(2).map(|k| k.handle), Some(3));
+        assert_eq!(arena.next_key(3), None);
+        // pairs never chain across steps
+        assert_eq!(arena.next_key(1), None);
+
+        assert_eq!(arena.pair(5), Pair::new(ParticleIndex(3), ParticleIndex(4)));
+        assert_eq!(arena.time(4), 1.5);
+        let p1 = arena.p1(2);
+        assert_eq!(p1.p, steps[0][2].p1().p);
+        assert_eq!(p1.v, steps[0][2].p1().v);
+        assert_eq!((p1.m, p1.t), (steps[0][2].p1().m, 0.7));
+        assert_eq!(arena.p2(2).r, 0.25);
+    }
+
+    #[test]
+    fn queue_walks_every_event_once() {
+        let steps: Vec<Vec<Rec>> = (0..3).map(|s| (0..60).map(|i| rec(i % 7, 7 + i % 5, s as f64 + (i * 13 % 60) as f64 / 60.0)).collect()).collect();
+        let arena = EventArena::from_steps(steps.iter());
+        let mut heap: ParBqueue<&KeyHandle> = ParBqueue::new(40, 0.1);
+        let seen: Vec<std::sync::atomic::AtomicUsize> = (0..arena.len()).map(|_| Default::default()).collect();
+        for step in 0..arena.steps() {
+            heap.bulk_push(arena.heads(step).par_iter().map(|&h| arena.key(h)));
+            while !heap.is_empty() {
+                heap.bulk_process(|k| {
+                    seen[k.handle as usize].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    arena.next_key(k.handle)
+                });
+            }
+        }
+        assert!(seen.iter().all(|s| s.load(std::sync::atomic::Ordering::Relaxed) == 1));
+    }
+}
@@ -0,0 +1,330 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem::size_of;
+
+use memmap2::Mmap;
+
+use crate::csvreader::{self, Rec};
+use crate::metadata::Metadata;
+
+// Layout, everything little-endian:
+//   header   magic "QTEV", version u32, record size u32, flags u32, record count u64, step count u64
+//   steps    with HAS_STEPS, step count + 1 u64 record indices, where every step starts plus the end
+//   metadata with HAS_METADATA, its length in bytes as a u64 and then the `# key=value` lines a
+//            text dump starts with, zero padded to a whole number of u64s
+//   records  record count Recs, in csv column order: the ids as u64s and then f64s. The ids are
+//            stored straight from their usizes, csvreader refuses to build where those are not 8 bytes
+// Everything before the records is whole u64s, so the records start 8 byte aligned and a mapped
+// file can hand them out in place.
+
+/// First bytes of every binary dump.
+pub const MAGIC: [u8; 4] = *b"QTEV";
+/// Bumped whenever the layout changes, older readers refuse newer files.
+pub const VERSION: u32 = 4; // 2 added the masses, 3 made the ids integers, 4 added the metadata
+
+const HAS_STEPS: u32 = 1;
+const HAS_METADATA: u32 = 2;
+const HEADER_LEN: usize = 32;
+
+fn header(count: usize, steps: Option<usize>, meta: &Metadata) -> [u8; HEADER_LEN] {
+    let mut h = [0; HEADER_LEN];
+    h[0..4].copy_from_slice(&MAGIC);
+    h[4..8].copy_from_slice(&VERSION.to_le_bytes());
+    h[8..12].copy_from_slice(&(size_of::<Rec>() as u32).to_le_bytes());
+    let flags = if steps.is_some() { HAS_STEPS } else { 0 } | if meta.is_empty() { 0 } else { HAS_METADATA };
+    h[12..16].copy_from_slice(&flags.to_le_bytes());
+    h[16..24].copy_from_slice(&(count as u64).to_le_bytes());
+    h[24..32].copy_from_slice(&(steps.unwrap_or(0) as u64).to_le_bytes());
+    h
+}
+
+fn write_records<'r, W: Write>(w: &mut W, recs: impl IntoIterator<Item = &'r Rec>) -> io::Result<()> {
+    for rec in recs {
+        if cfg!(target_endian = "little") {
+            w.write_all(bytemuck::bytes_of(rec))?;
+        } else {
+            // every column is 8 bytes, swapping them as f64s swaps the ids just the same
+            for v in bytemuck::cast::<Rec, [f64; csvreader::FIELDS]>(*rec) {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_metadata<W: Write>(w: &mut W, meta: &Metadata) -> io::Result<()> {
+    if meta.is_empty() {
+        return Ok(());
+    }
+    let mut text = Vec::new();
+    meta.write(&mut text)?;
+    w.write_all(&(text.len() as u64).to_le_bytes())?;
+    text.resize(text.len().next_multiple_of(8), 0);
+    w.write_all(&text)
+}
+
+/// Writes `recs` as a binary dump without a step table.
+pub fn write_dump<W: Write>(w: &mut W, recs: &[Rec], meta: &Metadata) -> io::Result<()> {
+    w.write_all(&header(recs.len(), None, meta))?;
+    write_metadata(w, meta)?;
+    write_records(w, recs)
+}
+
+/// Writes the records of each global step in turn, with a table of where every step starts, so
+/// a reader gets the steps back without working them out from the event times again.
+pub fn write_steps<'r, W: Write, I: IntoIterator<Item = &'r Rec> + Clone>(w: &mut W, steps: &[I], meta: &Metadata) -> io::Result<()> {
+    let mut bounds = vec![0u64];
+    for step in steps {
+        bounds.push(bounds.last().unwrap() + step.clone().into_iter().count() as u64);
+    }
+    w.write_all(&header(*bounds.last().unwrap() as usize, Some(steps.len()), meta))?;
+    for b in bounds {
+        w.write_all(&b.to_le_bytes())?;
+    }
+    write_metadata(w, meta)?;
+    for step in steps {
+        write_records(w, step.clone())?;
+    }
+    Ok(())
+}
+
+/// Converts a csv dump csvcon can read into a binary one at `out`.
+#[allow(dead_code)]
+pub fn csv_to_dump(csv: &String, out: &str) -> Result<(), Box<dyn Error>> {
+    let recs: Vec<Rec> = csvreader::csvcon(csv)?.into();
+    let meta = crate::metadata::open(csv)?;
+    let mut w = BufWriter::new(File::create(out)?);
+    write_dump(&mut w, &recs, &meta)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Whether the file at `path` starts like a binary dump.
+pub fn is_dump(path: &str) -> bool {
+    let mut magic = [0; 4];
+    File::open(path).and_then(|mut f| f.read_exact(&mut magic)).is_ok() && magic == MAGIC
+}
+
+/// A memory mapped binary dump. The records are read straight out of the mapping, nothing gets
+/// parsed or copied until a caller copies a Rec out.
+#[derive(Debug)]
+pub struct DumpFile {
+    map: Mmap,
+    count: usize,
+    steps: Option<usize>,
+    meta: Metadata,
+    start: usize, // where the records start
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+impl DumpFile {
+    /// Maps the dump at `path` and checks its header and step table against the file.
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        if cfg!(target_endian = "big") {
+            return Err("binary dumps are little-endian and can only be mapped on little-endian machines".into());
+        }
+        // the file is only ever read and the mapping lives as long as the DumpFile
+        let map = unsafe { Mmap::map(&File::open(path)?)? };
+        if map.len() < HEADER_LEN || map[0..4] != MAGIC {
+            return Err(format!("{} is not a binary event dump", path).into());
+        }
+        let version = u32_at(&map, 4);
+        if version != VERSION {
+            return Err(format!("{} is version {}, this build reads version {}", path, version, VERSION).into());
+        }
+        if u32_at(&map, 8) as usize != size_of::<Rec>() {
+            return Err(format!("{} has {} byte records, expected {}", path, u32_at(&map, 8), size_of::<Rec>()).into());
+        }
+        // every size in the header is checked against the file before anything gets sliced with it,
+        // so a corrupt header is an error and not an overflow or a panic
+        let count = u64_at(&map, 16);
+        let flags = u32_at(&map, 12);
+        let steps = (flags & HAS_STEPS != 0).then(|| u64_at(&map, 24));
+        let table = match steps {
+            Some(s) => s.checked_add(1).and_then(|n| n.checked_mul(8)),
+            None => Some(0),
+        };
+        let mut start = table.and_then(|t| usize::try_from(t).ok()).and_then(|t| t.checked_add(HEADER_LEN))
+            .filter(|&end| end <= map.len()).ok_or_else(|| format!("{} ends in its step table", path))?;
+        let mut meta = Metadata::new();
+        if flags & HAS_METADATA != 0 {
+            let ends = || format!("{} ends in its metadata", path);
+            let len = map.get(start..start + 8).map(|b| u64_at(b, 0)).ok_or_else(ends)?;
+            let text = usize::try_from(len).ok().and_then(|len| map.get(start + 8..(start + 8).checked_add(len)?)).ok_or_else(ends)?;
+            meta = Metadata::read(text).map_err(|e| format!("{} has broken metadata, {}", path, e))?;
+            // text fit in the file, so this can not overflow
+            start += 8 + text.len().next_multiple_of(8);
+        }
+        let size = usize::try_from(count).ok().and_then(|n| n.checked_mul(size_of::<Rec>())?.checked_add(start));
+        if size != Some(map.len()) {
+            return Err(format!("{} should hold {} records but is {} bytes long", path, count, map.len()).into());
+        }
+        // the step table sits before start, so it is all in the file
+        let (count, steps) = (count as usize, steps.map(|s| s as usize));
+        let dump = DumpFile { map, count, steps, meta, start };
+        if steps.is_some() {
+            let bounds: Vec<usize> = (0..=dump.steps().unwrap()).map(|i| dump.bound(i)).collect();
+            if bounds[0] != 0 || *bounds.last().unwrap() != count || bounds.windows(2).any(|w| w[0] > w[1]) {
+                return Err(format!("{} has a broken step table", path).into());
+            }
+        }
+        Ok(dump)
+    }
+
+    fn bound(&self, i: usize) -> usize {
+        u64_at(&self.map, HEADER_LEN + i * 8) as usize
+    }
+
+    /// Every record, in the order they were written.
+    pub fn records(&self) -> &[Rec] {
+        bytemuck::cast_slice(&self.map[self.start..])
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// What the dump says about the run it came from, empty for dumps written without any.
+    pub fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+
+    /// Number of global steps, if the dump was written with a step table.
+    pub fn steps(&self) -> Option<usize> {
+        self.steps
+    }
+
+    /// The records of global step `i`, if the dump has a step table.
+    pub fn step(&self, i: usize) -> Option<&[Rec]> {
+        (i < self.steps?).then(|| &self.records()[self.bound(i)..self.bound(i + 1)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::particle::{Particle, ParticleIndex};
+    use crate::vectors::Vector;
+
+    use super::*;
+
+    fn rec(p1: u32, p2: u32, time: f64) -> Rec {
+        let a = Particle::new(Vector::new(p1 as f64, 0.5, -1.0), Vector::new(0.0, 1.0, 0.25), 0.5, 1.0);
+        let b = Particle::new(Vector::new(p2 as f64, 0.0, 1e-9), Vector::new(-3.0, -1.0, 0.0), 0.25, 1.0);
+        Rec::new(ParticleIndex(p1 as usize), ParticleIndex(p2 as usize), &a, &b, time)
+    }
+
+    fn path(name: &str) -> String {
+        std::env::temp_dir().join(format!("queue_test_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn same(a: &[Rec], b: &[Rec]) -> bool {
+        bytemuck::cast_slice::<Rec, u8>(a) == bytemuck::cast_slice::<Rec, u8>(b)
+    }
+
+    #[test]
+    fn round_trips_with_and_without_steps() {
+        let steps: Vec<Vec<Rec>> = vec![vec![rec(1, 2, 0.1), rec(3, 4, 0.2)], vec![], vec![rec(1, 2, 2.5)]];
+        let flat: Vec<Rec> = steps.concat();
+
+        let p = path("flat.qtev");
+        write_dump(&mut File::create(&p).unwrap(), &flat, &Metadata::new()).unwrap();
+        assert!(is_dump(&p));
+        let dump = DumpFile::open(&p).unwrap();
+        assert_eq!(dump.len(), 3);
+        assert!(same(dump.records(), &flat));
+        assert_eq!(dump.steps(), None);
+        assert!(dump.step(0).is_none());
+        assert!(dump.metadata().is_empty());
+
+        // metadata of every length, so the padding after it gets tried
+        for units in ["", "s", "seconds", "years/2pi"] {
+            let meta = Metadata::read(format!("# step=1\n# particles=4\n# units={}\n", units).as_bytes()).unwrap();
+            let p = path("steps.qtev");
+            write_steps(&mut File::create(&p).unwrap(), &steps.iter().collect::<Vec<_>>(), &meta).unwrap();
+            let dump = DumpFile::open(&p).unwrap();
+            assert_eq!(dump.metadata(), &meta);
+            assert!(same(dump.records(), &flat));
+        }
+        let dump = DumpFile::open(&path("steps.qtev")).unwrap();
+        assert!(same(dump.records(), &flat));
+        assert_eq!(dump.steps(), Some(3));
+        assert!(same(dump.step(0).unwrap(), &steps[0]));
+        assert!(dump.step(1).unwrap().is_empty());
+        assert!(same(dump.step(2).unwrap(), &steps[2]));
+        assert!(dump.step(3).is_none());
+    }
+
+    #[test]
+    fn converts_csv() {
+        let csv = path("in.csv");
+        std::fs::write(&csv, "# step=0.01\n# particles=400\np1,p2,time,p1x,p1y,p1z,p1vx,p1vy,p1vz,p1r,p2x,p2y,p2z,p2vx,p2vy,p2vz,p2r\n\
+            113.0,137.0,2.5e-05,0.61,0.72,-0.36,0.0006,-0.0001,3e-05,0.001,0.62,0.72,-0.36,0.0009,-3e-05,-0.0002,0.001\n").unwrap();
+        let out = path("out.qtev");
+        csv_to_dump(&csv, &out).unwrap();
+        let dump = DumpFile::open(&out).unwrap();
+        let r = dump.records()[0];
+        assert_eq!((r.p1, r.p2, r.time, r.p1vz, r.p2r), (ParticleIndex(113), ParticleIndex(137), 2.5e-05, 3e-05, 0.001));
+        assert_eq!((dump.metadata().step, dump.metadata().particles), (Some(0.01), Some(400)));
+        assert!(!is_dump(&csv));
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_read() {
+        let mut bytes = Vec::new();
+        write_dump(&mut bytes, &[rec(1, 2, 0.5)], &Metadata::new()).unwrap();
+
+        let p = path("short.qtev");
+        std::fs::write(&p, &bytes[..bytes.len() - 8]).unwrap();
+        assert!(DumpFile::open(&p).unwrap_err().to_string().contains("bytes long"));
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION as u8 + 1;
+        std::fs::write(&p, &newer).unwrap();
+        assert!(DumpFile::open(&p).unwrap_err().to_string().contains(&format!("version {}", VERSION + 1)));
+
+        std::fs::write(&p, b"p1,p2,time\n").unwrap();
+        assert!(DumpFile::open(&p).is_err());
+
+        let mut meta = Metadata::new();
+        meta.step = Some(0.5);
+        let mut bytes = Vec::new();
+        write_dump(&mut bytes, &[rec(1, 2, 0.5)], &meta).unwrap();
+        std::fs::write(&p, &bytes[..HEADER_LEN + 12]).unwrap();
+        assert!(DumpFile::open(&p).unwrap_err().to_string().contains("ends in its metadata"));
+
+        // sizes in the header that overflow or point past the end of the file
+        let corrupt = |bytes: &[u8], at: usize, value: u64| {
+            let mut bytes = bytes.to_vec();
+            bytes[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&p, &bytes).unwrap();
+            DumpFile::open(&p).unwrap_err().to_string()
+        };
+        for count in [u64::MAX, u64::MAX / size_of::<Rec>() as u64, 2] {
+            assert!(corrupt(&bytes, 16, count).contains("bytes long"), "{}", count);
+        }
+        for len in [u64::MAX, u64::MAX - 7, 1 << 40] {
+            assert!(corrupt(&bytes, HEADER_LEN, len).contains("ends in its metadata"), "{}", len);
+        }
+        let mut stepped = Vec::new();
+        write_steps(&mut stepped, &[vec![&rec(1, 2, 0.5)], vec![&rec(3, 4, 1.5)]], &meta).unwrap();
+        for steps in [u64::MAX, u64::MAX / 8, 1 << 40] {
+            assert!(corrupt(&stepped, 24, steps).contains("ends in its step table"), "{}", steps);
+        }
+        assert!(corrupt(&stepped, HEADER_LEN + 8, u64::MAX).contains("broken step table"));
+        assert!(corrupt(&stepped, 24, 1).contains("bytes long"));
+    }
+}
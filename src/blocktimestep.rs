@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use rayon::prelude::*;
+
+use crate::{sequentialbucketqueue::HasKey, ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
+
+/// Anything that knows which time step the simulation picks for it, i.e. the dt that
+/// collisionhandling::get_next_time hands back for the pair at this event.
+pub trait HasTimestep: HasKey {
+    fn dt(&self) -> f64;
+}
+
+/// Block time-step scheduler the way N-body codes do it: bin n steps in blocks of dtmax/2^n, an event
+/// goes into the coarsest bin whose step is no longer than its own dt, and is due at the start of the
+/// block of that bin it falls in. Bins line up on power of two boundaries, so a block of bin n starts
+/// together with a block of every finer bin, and all the bins due at the same time get popped as one
+/// synchronized set. Within a set there is no ordering.
+#[derive(Debug)]
+pub struct BlockTimestep<T> {
+    dtmax: f64,
+    bins: Vec<BTreeMap<u64, Vec<T>>>, // per bin, keyed by the block start counted in the finest bin's steps
+    len: usize,
+}
+
+#[allow(dead_code)]
+impl<'a, T: HasTimestep> BlockTimestep<&'a T> {
+    /// `binnum` bins with steps dtmax, dtmax/2, ... dtmax/2^(binnum-1).
+    pub fn new(dtmax: f64, binnum: usize) -> Self {
+        let binnum = binnum.clamp(1, 63);
+        BlockTimestep {
+            dtmax,
+            bins: (0..binnum).map(|_| BTreeMap::new()).collect(),
+            len: 0,
+        }
+    }
+
+    /// Coarsest bin whose step still fits in `dt`. Anything not finite or bigger than dtmax goes in
+    /// bin 0 and anything smaller than the finest step goes in the last bin.
+    pub fn bin_of(&self, dt: f64) -> usize {
+        let last = self.bins.len() - 1;
+        if !dt.is_finite() || dt >= self.dtmax {
+            return 0;
+        }
+        if dt <= 0.0 {
+            return last;
+        }
+        ((self.dtmax / dt).log2().ceil() as usize).min(last)
+    }
+
+    pub fn step_of(&self, bin: usize) -> f64 {
+        self.dtmax / (1u64 << bin) as f64
+    }
+
+    // start of the block the element is due in, counted in steps of the finest bin
+    fn block(&self, elem: &T, bin: usize) -> u64 {
+        let index = (elem.key().0 / self.step_of(bin)).floor().max(0.0) as u64;
+        index << (self.bins.len() - 1 - bin)
+    }
+
+    pub fn push(&mut self, elem: &'a T) {
+        let bin = self.bin_of(elem.dt());
+        let block = self.block(elem, bin);
+        self.bins[bin].entry(block).or_default().push(elem);
+        self.len += 1;
+    }
+
+    // block start of the next synchronized set
+    fn next_block(&self) -> Option<u64> {
+        self.bins.iter().filter_map(|b| b.keys().next().copied()).min()
+    }
+
+    pub fn pop(&mut self) -> Option<&'a T> {
+        let block = self.next_block()?;
+        let bin = self.bins.iter_mut().find(|b| b.keys().next() == Some(&block)).unwrap();
+        let blockelems = bin.get_mut(&block).unwrap();
+        let y = blockelems.pop();
+        if blockelems.is_empty() {
+            bin.remove(&block);
+        }
+        self.len -= 1;
+        y
+    }
+
+    pub fn peek(&self) -> Option<&'a T> {
+        let block = self.next_block()?;
+        self.bins.iter().find_map(|b| b.get(&block)).and_then(|v| v.last().copied())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn clear(&mut self) {
+        for bin in self.bins.iter_mut() {
+            bin.clear();
+        }
+        self.len = 0;
+    }
+
+    /// Removes every synchronized set that is due before `time`. Elements come out by when their block
+    /// starts, so this can include elements whose own key is a little past `time`.
+    pub fn drain_until(&mut self, time: f64) -> Vec<&'a T> {
+        let mut ret = Vec::new();
+        while self.next_time().is_some_and(|t| t < time) {
+            ret.extend(self.bulk_pop_vec());
+        }
+        ret
+    }
+
+    fn bulk_pop_vec(&mut self) -> Vec<&'a T> {
+        let mut set = Vec::new();
+        if let Some(block) = self.next_block() {
+            for bin in self.bins.iter_mut() {
+                if let Some(mut blockelems) = bin.remove(&block) {
+                    set.append(&mut blockelems);
+                }
+            }
+        }
+        self.len -= set.len();
+        set
+    }
+
+    /// Number of elements waiting in each bin, finest last.
+    pub fn bin_sizes(&self) -> Vec<usize> {
+        self.bins.iter().map(|b| b.values().map(Vec::len).sum()).collect()
+    }
+
+    /// Time the next synchronized set is due at.
+    pub fn next_time(&self) -> Option<f64> {
+        self.next_block().map(|b| b as f64 * self.step_of(self.bins.len() - 1))
+    }
+
+    pub fn bulk_process<F: Fn(&'a T) -> Option<&'a T> + Sync + Send>(&mut self, f: F) where T: Sync {
+        let set: Vec<&'a T> = self.bulk_pop().collect();
+        let mapped: Vec<&'a T> = set.into_par_iter().filter_map(f).collect();
+        self.bulk_push(mapped.into_par_iter());
+    }
+
+    pub fn bulk_push<I: ParallelIterator<Item = &'a T>>(&mut self, es: I) where T: Sync {
+        let elems: Vec<&'a T> = es.collect();
+        for elem in elems {
+            self.push(elem);
+        }
+    }
+
+    /// Pops every bin that is due at the next block start together.
+    pub fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a T> where T: Sync {
+        self.bulk_pop_vec().into_par_iter()
+    }
+}
+
+impl<'a, E: Ord + HasTimestep> SeqentialPriorityQueue<'a, E> for BlockTimestep<&'a E> {
+    fn push(&mut self, e: &'a E) {
+        BlockTimestep::push(self, e);
+    }
+    fn pop(&mut self) -> Option<&'a E> {
+        BlockTimestep::pop(self)
+    }
+    fn peek(&self) -> Option<&'a E> {
+        BlockTimestep::peek(self)
+    }
+    fn is_empty(&self) -> bool {
+        BlockTimestep::is_empty(self)
+    }
+    fn len(&self) -> usize {
+        BlockTimestep::len(self)
+    }
+    fn clear(&mut self) {
+        BlockTimestep::clear(self)
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        BlockTimestep::drain_until(self, time)
+    }
+    fn pop_order(&self) -> PopOrder {
+        PopOrder::Within(self.dtmax)
+    }
+}
+
+impl<'a, E: Ord + HasTimestep + Send + Sync> ParallelPriorityQueue<'a, E> for BlockTimestep<&'a E> {
+    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
+        BlockTimestep::bulk_process(self, f);
+    }
+    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
+        BlockTimestep::bulk_push(self, es);
+    }
+    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
+        BlockTimestep::bulk_pop(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use ordered_float::OrderedFloat;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Ev(f64, f64); // time, dt
+
+    impl HasKey for Ev {
+        fn key(&self) -> OrderedFloat<f64> {
+            OrderedFloat(self.0)
+        }
+    }
+
+    impl HasTimestep for Ev {
+        fn dt(&self) -> f64 {
+            self.1
+        }
+    }
+
+    #[test]
+    fn bins_follow_dt() {
+        let q: BlockTimestep<&Ev> = BlockTimestep::new(1.0, 4);
+        assert_eq!(q.bin_of(f64::INFINITY), 0);
+        assert_eq!(q.bin_of(1.0), 0);
+        assert_eq!(q.bin_of(0.7), 1);
+        assert_eq!(q.bin_of(0.5), 1);
+        assert_eq!(q.bin_of(0.3), 2);
+        assert_eq!(q.bin_of(1e-9), 3);
+        assert_eq!(q.bin_of(0.0), 3);
+        assert_eq!(q.step_of(3), 0.125);
+    }
+
+    #[test]
+    fn pops_synchronized_sets() {
+        let evs = [Ev(0.1, 1.0), Ev(0.3, 0.5), Ev(0.2, 0.125), Ev(0.6, 0.5), Ev(0.55, 0.125), Ev(0.05, 0.125)];
+        let mut q: BlockTimestep<&Ev> = BlockTimestep::new(1.0, 4);
+        q.bulk_push(evs.par_iter());
+        assert_eq!(q.len(), 6);
+        assert_eq!(q.bin_sizes(), vec![1, 2, 0, 3]);
+
+        // block 0 of every bin starts at 0
+        assert_eq!(q.next_time(), Some(0.0));
+        let mut set: Vec<&Ev> = q.bulk_pop().collect();
+        set.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        assert_eq!(set, vec![&evs[5], &evs[0], &evs[1]]);
+
+        assert_eq!(q.next_time(), Some(0.125));
+        assert_eq!(q.peek(), Some(&evs[2]));
+        assert_eq!(q.pop(), Some(&evs[2]));
+
+        // 0.5 is the start of bin 1's second block and bin 3's fifth one
+        assert_eq!(q.next_time(), Some(0.5));
+        let set: Vec<&Ev> = q.bulk_pop().collect();
+        assert_eq!(set.len(), 2);
+        assert!(q.is_empty());
+        assert_eq!(q.bulk_pop().count(), 0);
+    }
+}
@@ -203,6 +203,27 @@ fn process_pair_get_dv(
     ret
 }
 
+/// The step get_next_time picks for a pair meeting at `event_time`, worked out on copies so
+/// neither particle moves.
+pub fn timestep(mut p1: Particle, mut p2: Particle, event_time: f64) -> f64 {
+    fast_forward(&mut p1, event_time);
+    fast_forward(&mut p2, event_time);
+    let relative_speed_estimate = (p1.v - p2.v).mag();
+    let (_, _, info) = compute_acc(&mut p1, &mut p2);
+    let (_, dt) = get_next_time(
+        info.separation_distance,
+        info.impact_speed,
+        event_time,
+        info.k,
+        info.reduced_mass,
+        info.b,
+        relative_speed_estimate,
+        p1.r,
+        p2.r,
+    );
+    dt
+}
+
 pub fn process_collision(p1: &mut Particle, p2: &mut Particle, event_time: f64) -> f64 {
     fast_forward(p1, event_time);
     fast_forward(p2, event_time);
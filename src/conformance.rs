@@ -0,0 +1,434 @@
+//! Conformance checks every queue in the crate has to pass, generic over the queue traits. Each queue
+//! declares through pop_order how close to the smallest key its pops are, and gets held to exactly
+//! that against a BinaryHeap reference model. A new queue only needs a test at the bottom of this file
+//! calling `sequential` or `parallel` with a constructor.
+//!
+//! Pushes only ever use keys no earlier than the last one popped, which is all a simulation does and
+//! all LogBqueue and TimingWheel promise to handle.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use ordered_float::OrderedFloat;
+use rayon::prelude::*;
+
+use crate::blocktimestep::HasTimestep;
+use crate::sequentialbucketqueue::HasKey;
+use crate::uniquequeue::HasId;
+use crate::{ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
+
+const HORIZON: f64 = 50.0;
+const POOL: usize = 3000;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ev {
+    key: OrderedFloat<f64>,
+    id: u32,
+    dt: OrderedFloat<f64>,
+}
+
+impl HasKey for Ev {
+    fn key(&self) -> OrderedFloat<f64> {
+        self.key
+    }
+}
+
+impl HasId for Ev {
+    type Id = u32;
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl HasTimestep for Ev {
+    fn dt(&self) -> f64 {
+        self.dt.0
+    }
+}
+
+/// Events with keys on a 0.05 grid, so there are plenty of ties and keys right on bucket edges.
+pub fn pool(seed: u64) -> Vec<Ev> {
+    let rng = fastrand::Rng::with_seed(seed);
+    (0..POOL as u32).map(|id| Ev {
+        key: OrderedFloat((rng.f64() * HORIZON / 0.05).floor() * 0.05),
+        id,
+        dt: OrderedFloat(rng.f64() * 2.0),
+    }).collect()
+}
+
+/// Events with keys that are whole multiples of `width`. For a width like main's DELTA, `i*width/width`
+/// rounds to just below `i` for some of them, so a queue can only agree with itself about which
+/// bucket those go in if it works the bucket out the same way every time.
+pub fn edge_pool(seed: u64, width: f64) -> Vec<Ev> {
+    let rng = fastrand::Rng::with_seed(seed);
+    (0..POOL as u32).map(|id| Ev {
+        key: OrderedFloat(rng.usize(0..EDGES) as f64 * width),
+        id,
+        dt: OrderedFloat(rng.f64() * 2.0 * width),
+    }).collect()
+}
+
+// how many multiples of the width edge_pool picks keys from
+const EDGES: usize = 200;
+
+// hands out the pool in order, skipping whatever is already in the past
+struct Source<'a> {
+    evs: &'a [Ev],
+    next: usize,
+}
+
+impl<'a> Source<'a> {
+    fn take(&mut self, from: f64) -> Option<&'a Ev> {
+        while let Some(e) = self.evs.get(self.next) {
+            self.next += 1;
+            if e.key.0 >= from {
+                return Some(e);
+            }
+        }
+        None
+    }
+}
+
+type Model<'a> = BinaryHeap<Reverse<&'a Ev>>;
+
+fn bucket(key: f64, width: f64) -> f64 {
+    (key / width).floor()
+}
+
+fn contains(model: &Model, e: &Ev) -> bool {
+    model.iter().any(|r| std::ptr::eq(r.0, e))
+}
+
+fn remove(model: &mut Model, e: &Ev) {
+    assert!(contains(model, e), "{e:?} was never pushed or already came out");
+    model.retain(|r| !std::ptr::eq(r.0, e));
+}
+
+// panics unless the queue was allowed to hand out `e` next
+fn check_next(order: PopOrder, model: &Model, e: &Ev) {
+    assert!(contains(model, e), "{e:?} was never pushed or already came out");
+    let min = model.peek().unwrap().0.key.0;
+    let key = e.key.0;
+    match order {
+        PopOrder::Exact | PopOrder::Bucketed { sorted: true, .. } => assert_eq!(key, min, "{order:?}"),
+        PopOrder::Bucketed { width, .. } => assert_eq!(bucket(key, width), bucket(min, width), "{key} is not in the bucket of {min}, {order:?}"),
+        PopOrder::Within(slack) => assert!(key >= min && key < min + slack, "{key} is too far past {min}, {order:?}"),
+    }
+}
+
+fn check_len<'a, Q: SeqentialPriorityQueue<'a, Ev>>(q: &Q, model: &Model) {
+    assert_eq!(q.len(), model.len());
+    assert_eq!(q.is_empty(), model.is_empty());
+    match q.peek() {
+        Some(e) => check_next(q.pop_order(), model, e),
+        None => assert!(model.is_empty(), "peek came back empty with {} left", model.len()),
+    }
+}
+
+fn emptiness<'a, Q: SeqentialPriorityQueue<'a, Ev>>(mut q: Q, evs: &'a [Ev]) {
+    assert!(q.is_empty());
+    assert_eq!(q.len(), 0);
+    assert_eq!(q.peek(), None);
+    assert_eq!(q.pop(), None);
+
+    q.push(&evs[0]);
+    assert!(!q.is_empty());
+    assert_eq!(q.len(), 1);
+    assert_eq!(q.peek(), Some(&evs[0]));
+    assert_eq!(q.pop(), Some(&evs[0]));
+    assert!(q.is_empty());
+    assert_eq!(q.pop(), None);
+
+    for e in &evs[..20] {
+        q.push(e);
+    }
+    assert_eq!(q.len(), 20);
+    q.clear();
+    assert!(q.is_empty());
+    assert_eq!(q.len(), 0);
+    assert_eq!(q.peek(), None);
+    assert_eq!(q.pop(), None);
+
+    // and it still works after a clear
+    q.push(&evs[1]);
+    assert_eq!(q.pop(), Some(&evs[1]));
+    assert!(q.is_empty());
+}
+
+fn interleaved<'a, Q: SeqentialPriorityQueue<'a, Ev>>(mut q: Q, evs: &'a [Ev]) {
+    let rng = fastrand::Rng::with_seed(7);
+    let mut model = Model::new();
+    let mut source = Source { evs, next: 0 };
+    let mut last = 0.0;
+    loop {
+        // a few more pushes than pops so the queue fills up on the way
+        if rng.f64() < 0.55 {
+            let Some(e) = source.take(last) else { break };
+            q.push(e);
+            model.push(Reverse(e));
+        } else {
+            let order = q.pop_order();
+            match q.pop() {
+                Some(e) => {
+                    check_next(order, &model, e);
+                    remove(&mut model, e);
+                    last = e.key.0;
+                }
+                None => assert!(model.is_empty(), "pop came back empty with {} left", model.len()),
+            }
+        }
+        check_len(&q, &model);
+    }
+    while !model.is_empty() {
+        let order = q.pop_order();
+        let e = q.pop().unwrap();
+        check_next(order, &model, e);
+        remove(&mut model, e);
+        check_len(&q, &model);
+    }
+    assert_eq!(q.pop(), None);
+}
+
+fn drain<'a, Q: SeqentialPriorityQueue<'a, Ev>>(mut q: Q, evs: &'a [Ev]) {
+    let mut model = Model::new();
+    for e in &evs[..300] {
+        q.push(e);
+        model.push(Reverse(e));
+    }
+    // one time inside a bucket and one right on an edge
+    for time in [7.325, 20.0] {
+        let order = q.pop_order();
+        let drained = q.drain_until(time);
+        for e in &drained {
+            remove(&mut model, e);
+        }
+        // block time-steps go by when a block starts, so they may take a bit more than asked
+        let slack = match order {
+            PopOrder::Within(slack) => slack,
+            _ => 0.0,
+        };
+        assert!(drained.iter().all(|e| e.key.0 < time + slack), "drained past {time}");
+        assert!(model.iter().all(|r| r.0.key.0 >= time), "left something before {time}");
+        check_len(&q, &model);
+    }
+    while let Some(e) = q.pop() {
+        check_next(q.pop_order(), &model, e);
+        remove(&mut model, e);
+    }
+    assert!(model.is_empty());
+}
+
+fn buckets<'a, Q: ParallelPriorityQueue<'a, Ev>>(mut q: Q, evs: &'a [Ev]) {
+    let rng = fastrand::Rng::with_seed(11);
+    let mut model = Model::new();
+    let mut source = Source { evs, next: 0 };
+    let first: Vec<&Ev> = (0..200).filter_map(|_| source.take(0.0)).collect();
+    q.bulk_push(first.par_iter().copied());
+    model.extend(first.iter().map(|e| Reverse(*e)));
+    check_len(&q, &model);
+
+    let mut last = 0.0;
+    let mut buf = Vec::new();
+    while !q.is_empty() {
+        let order = q.pop_order();
+        // both ways of popping a bucket have to agree
+        let set: Vec<&Ev> = if rng.bool() {
+            q.bulk_pop().collect()
+        } else {
+            q.bulk_pop_into(&mut buf);
+            buf.clone()
+        };
+        assert!(!set.is_empty(), "bulk_pop came back empty with {} left", model.len());
+        let min = model.peek().unwrap().0.key.0;
+        for e in &set {
+            remove(&mut model, e);
+        }
+        match order {
+            PopOrder::Exact => assert!(set.iter().any(|e| e.key.0 == min)),
+            PopOrder::Bucketed { width, sorted } => {
+                assert!(set.iter().all(|e| bucket(e.key.0, width) == bucket(min, width)), "bulk_pop went past the bucket of {min}");
+                assert!(!sorted || set.windows(2).all(|w| w[0] <= w[1]), "bulk_pop of a sorted queue came out of order");
+                assert!(model.iter().all(|r| bucket(r.0.key.0, width) != bucket(min, width)), "bulk_pop left part of the bucket of {min}");
+            }
+            PopOrder::Within(slack) => assert!(set.iter().all(|e| e.key.0 >= min && e.key.0 < min + slack)),
+        }
+        last = set.iter().fold(last, |a, e| e.key.0.max(a));
+        check_len(&q, &model);
+
+        // schedule a few later events, like bulk_process would
+        let more: Vec<&Ev> = (0..rng.usize(0..40)).filter_map(|_| source.take(last)).collect();
+        q.bulk_push(more.par_iter().copied());
+        model.extend(more.iter().map(|e| Reverse(*e)));
+        check_len(&q, &model);
+    }
+    assert!(model.is_empty());
+    assert_eq!(q.bulk_pop().count(), 0);
+    q.bulk_pop_into(&mut buf);
+    assert!(buf.is_empty());
+}
+
+fn process<'a, Q: ParallelPriorityQueue<'a, Ev>>(mut q: Q, evs: &'a [Ev]) {
+    q.bulk_push(evs[..200].par_iter());
+    // handing everything back leaves the queue as it was
+    q.bulk_process(Some);
+    assert_eq!(q.len(), 200);
+    let mut rounds = 0;
+    while !q.is_empty() {
+        q.bulk_process(|_| None);
+        rounds += 1;
+        assert!(rounds <= 200, "bulk_process is not taking anything out");
+    }
+    assert_eq!(q.len(), 0);
+}
+
+/// Runs every one element at a time check on fresh queues from `make`.
+pub fn sequential<'a, Q: SeqentialPriorityQueue<'a, Ev>>(evs: &'a [Ev], make: impl Fn() -> Q) {
+    emptiness(make(), evs);
+    interleaved(make(), evs);
+    drain(make(), evs);
+}
+
+/// Everything `sequential` checks plus bulk_pop boundaries and bulk_process.
+pub fn parallel<'a, Q: ParallelPriorityQueue<'a, Ev>>(evs: &'a [Ev], make: impl Fn() -> Q) {
+    sequential(evs, &make);
+    buckets(make(), evs);
+    process(make(), evs);
+}
+
+mod queues {
+
+    use std::collections::BinaryHeap;
+
+    use crate::adapters::{Locked, Sequential};
+    use crate::arena::{EventArena, Handle, KeyHandle};
+    use crate::blocktimestep::BlockTimestep;
+    use crate::csvreader::Rec;
+    use crate::logbucketqueue::{LevelOrder, LogBqueue};
+    use crate::parallelbucketqueue::ParBqueue;
+    use crate::partition;
+    use crate::parwithmutex::ParaBqueue;
+    use crate::particle::{Particle, ParticleIndex};
+    use crate::sequentialbucketqueue::Bqueue;
+    use crate::timingwheel::TimingWheel;
+    use crate::tuning::Retuner;
+    use crate::uniquequeue::{DuplicatePolicy, UniqueQueue};
+    use crate::vectors::Vector;
+
+    use super::*;
+
+    // enough buckets of width 1 to cover the pool's keys
+    const BUCKETS: usize = HORIZON as usize + 2;
+
+    #[test]
+    fn binary_heap() {
+        let evs = pool(1);
+        sequential(&evs, BinaryHeap::new);
+    }
+
+    #[test]
+    fn bqueue() {
+        let evs = pool(2);
+        sequential(&evs, || Bqueue::new(BUCKETS, 1.0));
+        sequential(&evs, || Bqueue::new(BUCKETS * 4, 0.25));
+        sequential(&evs, || Bqueue::with_retuner(BUCKETS, 1.0, Retuner::new(4.0, 1.5, 4)));
+    }
+
+    #[test]
+    fn par_bqueue() {
+        let evs = pool(3);
+        parallel(&evs, || ParBqueue::new(BUCKETS, 1.0));
+        parallel(&evs, || ParBqueue::with_retuner(BUCKETS, 1.0, Retuner::new(4.0, 1.5, 4)));
+        sequential(&evs, || Sequential(ParBqueue::new(BUCKETS, 1.0)));
+    }
+
+    #[test]
+    fn para_bqueue() {
+        let evs = pool(9);
+        parallel(&evs, || ParaBqueue::new(BUCKETS, 1.0));
+        parallel(&evs, || UniqueQueue::new(ParaBqueue::new(BUCKETS, 1.0), DuplicatePolicy::KeepEarliest));
+    }
+
+    // DELTA from main, the sort of width that does not divide its own multiples evenly
+    #[test]
+    fn keys_on_bucket_edges() {
+        let width = 2.0 * std::f64::consts::PI * 1E-4 - 2.0 * std::f64::consts::PI * 1E-5;
+        assert_eq!(((63.0 * width) / width).floor(), 62.0);
+        let evs = edge_pool(10, width);
+        parallel(&evs, || Locked::new(BinaryHeap::new(), width));
+        parallel(&evs, || Locked::new(Bqueue::new(EDGES + 2, width), width));
+        parallel(&evs, || ParBqueue::new(EDGES + 2, width));
+        parallel(&evs, || ParaBqueue::new(EDGES + 2, width));
+        sequential(&evs, || Bqueue::new(EDGES + 2, width));
+    }
+
+    #[test]
+    fn log_bqueue() {
+        let evs = pool(4);
+        parallel(&evs, || LogBqueue::new(0.1, 3, LevelOrder::Exact));
+        parallel(&evs, || LogBqueue::new(0.1, 2, LevelOrder::Relaxed));
+    }
+
+    #[test]
+    fn timing_wheel() {
+        let evs = pool(5);
+        parallel(&evs, || TimingWheel::new(0.1, 3, 4));
+        // two small wheels so plenty has to wait in overflow
+        parallel(&evs, || TimingWheel::new(0.5, 2, 2));
+    }
+
+    #[test]
+    fn block_timestep() {
+        let evs = pool(6);
+        parallel(&evs, || BlockTimestep::new(1.0, 6));
+    }
+
+    #[test]
+    fn unique_queue() {
+        let evs = pool(7);
+        sequential(&evs, || UniqueQueue::new(BinaryHeap::new(), DuplicatePolicy::KeepEarliest));
+        parallel(&evs, || UniqueQueue::new(ParBqueue::new(BUCKETS, 1.0), DuplicatePolicy::Replace));
+    }
+
+    #[test]
+    fn locked() {
+        let evs = pool(8);
+        parallel(&evs, || Locked::new(BinaryHeap::new(), 1.0));
+        parallel(&evs, || Locked::new(Bqueue::new(BUCKETS, 1.0), 1.0));
+    }
+
+    // the arena's KeyHandles have to come out of a queue in the order the KeyVals they stand for do,
+    // ties on the time included, or arena runs process a bucket in another order than the rest
+    #[test]
+    fn arena_orders_like_keyval() {
+        let p = Particle::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, 0.0), 1.0, 1.0);
+        // 40 distinct times and 15 pairs, half of them written the wrong way round
+        let recs: Vec<Rec> = (0..400).map(|i| {
+            let (a, b) = (ParticleIndex(i * 7 % 5), ParticleIndex(5 + i % 3));
+            let (a, b) = if i % 2 == 0 { (a, b) } else { (b, a) };
+            Rec::new(a, b, &p, &p, (i * 13 % 40) as f64 / 20.0)
+        }).collect();
+        let data = partition::partition(&recs, 2, |_, r| Some((r.time >= 1.0) as usize)).unwrap();
+        let arena = EventArena::from_steps(data.steps().map(|step| step.iter().map(|k| &k.val)));
+
+        let mut first = 0;
+        for step in data.steps() {
+            let keys: Vec<&KeyHandle> = (first..first + step.len()).map(|h| arena.key(h as Handle)).collect();
+            first += step.len();
+            for (a, ka) in step.iter().zip(&keys) {
+                for (b, kb) in step.iter().zip(&keys) {
+                    assert_eq!(a.cmp(b), ka.cmp(kb), "{:?} and {:?}", (a.key, a.id, a.index), (b.key, b.id, b.index));
+                }
+            }
+
+            // pushed backwards, so file order is no help
+            let mut by_val = Bqueue::new(BUCKETS, 0.25);
+            let mut by_handle = Bqueue::new(BUCKETS, 0.25);
+            step.iter().rev().for_each(|k| by_val.push(k));
+            keys.iter().rev().for_each(|k| by_handle.push(*k));
+            while let Some(k) = by_val.pop() {
+                let h = by_handle.pop().unwrap();
+                assert_eq!((k.key, k.id, k.index), (h.key, h.pair, h.index as usize));
+            }
+            assert!(by_handle.is_empty());
+        }
+    }
+}
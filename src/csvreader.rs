@@ -2,16 +2,21 @@ use std::{ cmp, error::Error};
 use csv;
 use serde;
 use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Range;
+use rayon::prelude::*;
 
 use cmp::Ordering;
 
-use crate::{particle::Particle, vectors::Vector};
+use crate::{particle::{self, Pair, Particle, ParticleIndex}, vectors::Vector};
 
-#[derive(Debug, serde::Deserialize,Clone, Copy)]
+// repr(C) and Pod so binary dumps can hand out their records in place, see bindump
+#[derive(Debug, serde::Deserialize, serde::Serialize,Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
 //#[serde(rename_all = "PascalCase")]
 pub struct Rec {
-    pub p1:f64, 
-    pub p2:f64,
+    pub p1:ParticleIndex, 
+    pub p2:ParticleIndex,
     pub time:f64,
     pub p1x:f64,
     pub p1y:f64,
@@ -26,11 +31,28 @@ pub struct Rec {
     pub p2vx:f64, 
     pub p2vy:f64, 
     pub p2vz:f64, 
-    pub p2r:f64
+    pub p2r:f64,
+    #[serde(default = "no_mass")]
+    pub p1m:f64, // masses, NaN when the dump did not give them, see resolve_masses
+    #[serde(default = "no_mass")]
+    pub p2m:f64
 }
 
+fn no_mass() -> f64 {
+    f64::NAN
+}
+
+/// Number of columns in a Rec, all of them 8 bytes: the two ids and then f64s.
+pub const FIELDS: usize = 19;
+
+// the id columns, which are not floats
+const IDS: usize = 2;
+
+// the ids are usizes, dumps and the casts to [f64; FIELDS] only work where those are 8 bytes
+const _: () = assert!(std::mem::size_of::<Rec>() == FIELDS * 8, "a Rec has to be FIELDS 8 byte columns");
+
 impl Rec {
-    pub fn new(i1: f64, i2: f64, p1: &Particle, p2: &Particle, event_time: f64) -> Self {
+    pub fn new(i1: ParticleIndex, i2: ParticleIndex, p1: &Particle, p2: &Particle, event_time: f64) -> Self {
         Rec {
             p1: i1,
             p2: i2,
@@ -49,24 +71,65 @@ impl Rec {
             p2vy: p2.v.y(),
             p2vz: p2.v.z(),
             p2r: p2.r,
+            p1m: p1.m,
+            p2m: p2.m,
+        }
+    }
+
+    /// The event's pair, the same whichever way round the dump wrote it.
+    pub fn pair(&self) -> Pair {
+        Pair::new(self.p1, self.p2)
+    }
+
+    /// The columns after the ids, in field order.
+    pub fn values(&self) -> [f64; FIELDS - IDS] {
+        bytemuck::cast::<Rec, [f64; FIELDS]>(*self)[IDS..].try_into().unwrap()
+    }
+
+    /// Builds a record from the ids and the rest of the columns in field order.
+    pub fn from_values(p1: ParticleIndex, p2: ParticleIndex, values: &[f64]) -> Self {
+        let mut v = [0.0; FIELDS];
+        v[IDS..].copy_from_slice(values);
+        let mut rec: Rec = bytemuck::cast(v);
+        (rec.p1, rec.p2) = (p1, p2);
+        rec
+    }
+
+    /// Gives every particle the dump has no mass for the mass of a sphere of density `rho`.
+    pub fn resolve_masses(&mut self, rho: f64) {
+        if self.p1m.is_nan() {
+            self.p1m = Particle::mass_from_radius(self.p1r, rho);
         }
+        if self.p2m.is_nan() {
+            self.p2m = Particle::mass_from_radius(self.p2r, rho);
+        }
+    }
+
+    // the particle as it is at the event, masses nobody resolved go by the default density
+    fn particle(&self, p: Vector, v: Vector, r: f64, m: f64) -> Particle {
+        let mut particle = Particle::new(p, v, r, particle::DEFAULT_DENSITY);
+        if !m.is_nan() {
+            particle.m = m;
+        }
+        particle.t = self.time;
+        particle
     }
 
     pub fn p1(&self) -> Particle {
-        Particle::new(
+        self.particle(
             Vector::new(self.p1x, self.p1y, self.p1z),
             Vector::new(self.p1vx, self.p1vy, self.p1vz),
             self.p1r,
-            1.0
+            self.p1m
         )
     }
 
     pub fn p2(&self) -> Particle {
-        Particle::new(
+        self.particle(
             Vector::new(self.p2x, self.p2y, self.p2z),
             Vector::new(self.p2vx, self.p2vy, self.p2vz),
             self.p2r,
-            1.0
+            self.p2m
         )
     }
 }
@@ -86,14 +149,391 @@ impl PartialEq for Rec {
 
 pub fn csvcon(file: &String) ->Result<VecDeque<Rec>, Box<dyn Error>>  {
     let file_path = file.replace("\"","").replace("\\","/");
-    println!("{}1",file_path);
-    let mut rdr = csv::Reader::from_path(file_path.trim())?;
+    let mut rdr = reader(file_path.trim())?;
+    let schema = Schema::new(rdr.byte_headers()?)?;
 
     let mut matrix:VecDeque<Rec> = VecDeque::new();
-    for result in rdr.deserialize() {
-        let record:Rec = result?;
+    for result in rdr.byte_records() {
+        let record:Rec = schema.rec_at(&result?)?;
         matrix.push_back(record);
     }
     return Ok(matrix);
    
-}
\ No newline at end of file
+}
+
+// `#` lines are the dump's metadata, see metadata::Metadata
+fn reader(path: &str) -> csv::Result<csv::Reader<std::fs::File>> {
+    csv::ReaderBuilder::new().comment(Some(b'#')).from_path(path)
+}
+
+/// The line of the file a row starts on, counting from 1.
+pub fn line_of(record: &csv::ByteRecord) -> u64 {
+    record.position().map_or(0, |p| p.line())
+}
+
+/// Column names as Rec calls them, in field order.
+pub const COLUMNS: [&str; FIELDS] = ["p1", "p2", "time", "p1x", "p1y", "p1z", "p1vx", "p1vy", "p1vz", "p1r", "p2x", "p2y", "p2z", "p2vx", "p2vy", "p2vz", "p2r", "p1m", "p2m"];
+
+// how many leading columns a dump can not do without
+const REQUIRED: usize = 3;
+
+// other names dumps use for Rec's columns, after normalise
+const ALIASES: [(&str, &str); 2] = [("p1mass", "p1m"), ("p2mass", "p2m")];
+
+// density columns, used for the mass when a dump has them instead of masses
+const DENSITIES: [[&str; 2]; 2] = [["p1rho", "p1density"], ["p2rho", "p2density"]];
+const MASS: [usize; 2] = [17, 18];
+const RADIUS: [usize; 2] = [9, 16];
+
+// "P1.x", "p1_x" and " p1x " all name the same column
+fn normalise(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).chars().filter(|c| !matches!(c, '.' | '_') && !c.is_whitespace()).collect::<String>().to_lowercase()
+}
+
+/// Which column of a csv dump holds what, worked out from its header. Rec's columns are found by
+/// name in any order. Apart from p1, p2 and time any of them can be missing and read as zero, which
+/// covers dumps with only the three of them and 2-D dumps without the z columns. Missing masses
+/// are NaN instead, or worked out from the radius when the dump has a density column for that
+/// particle. Columns Rec has no field for are kept as extras for whoever wants them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    columns: [Option<usize>; FIELDS],
+    densities: [Option<usize>; 2],
+    extras: Vec<(String, usize)>,
+}
+
+impl Schema {
+    pub fn new(headers: &csv::ByteRecord) -> Result<Self, Box<dyn Error>> {
+        let mut columns = [None; FIELDS];
+        let mut densities = [None; 2];
+        let mut extras = Vec::new();
+        for (at, header) in headers.iter().enumerate() {
+            let name = normalise(header);
+            let name = ALIASES.iter().find(|(alias, _)| *alias == name).map_or(name.as_str(), |(_, to)| to);
+            if let Some(p) = DENSITIES.iter().position(|names| names.contains(&name)) {
+                densities[p] = Some(at);
+                continue;
+            }
+            match COLUMNS.iter().position(|c| *c == name) {
+                Some(field) if columns[field].is_some() => return Err(format!("column `{}` appears twice", name).into()),
+                Some(field) => columns[field] = Some(at),
+                None => extras.push((String::from_utf8_lossy(header).trim().to_string(), at)),
+            }
+        }
+        if let Some(missing) = (0..REQUIRED).find(|&f| columns[f].is_none()) {
+            return Err(format!("no `{}` column", COLUMNS[missing]).into());
+        }
+        Ok(Schema { columns, densities, extras })
+    }
+
+    /// Whether the dump has no z columns at all.
+    #[allow(dead_code)]
+    pub fn is_2d(&self) -> bool {
+        [5, 8, 12, 15].iter().all(|&f| self.columns[f].is_none())
+    }
+
+    /// Names of the extra columns, in file order.
+    pub fn extra_names(&self) -> impl Iterator<Item = &str> {
+        self.extras.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Reads one row, missing columns as zero and missing masses as NaN. Ids have to be whole numbers.
+    pub fn rec(&self, record: &csv::ByteRecord) -> Result<Rec, Box<dyn Error>> {
+        let text = |at: usize, name: &str| -> Result<&str, Box<dyn Error>> {
+            let raw = record.get(at).ok_or_else(|| format!("row too short for `{}`", name))?;
+            Ok(std::str::from_utf8(raw)?.trim())
+        };
+        let field = |at: usize, name: &str| -> Result<f64, Box<dyn Error>> {
+            let text = text(at, name)?;
+            Ok(text.parse().map_err(|e| format!("`{}` is `{}`: {}", name, text, e))?)
+        };
+        let id = |f: usize| -> Result<ParticleIndex, Box<dyn Error>> {
+            // REQUIRED makes sure both id columns are there
+            let text = text(self.columns[f].unwrap(), COLUMNS[f])?;
+            Ok(text.parse().map_err(|e| format!("`{}`: {}", COLUMNS[f], e))?)
+        };
+        let mut v = [0.0; FIELDS];
+        for (f, column) in self.columns.iter().enumerate().skip(IDS) {
+            v[f] = match column {
+                Some(at) => field(*at, COLUMNS[f])?,
+                None if MASS.contains(&f) => f64::NAN,
+                None => 0.0,
+            };
+        }
+        for p in 0..2 {
+            if let (None, Some(at)) = (self.columns[MASS[p]], self.densities[p]) {
+                v[MASS[p]] = Particle::mass_from_radius(v[RADIUS[p]], field(at, DENSITIES[p][0])?);
+            }
+        }
+        Ok(Rec::from_values(id(0)?, id(1)?, &v[IDS..]))
+    }
+
+    /// rec, with the line the row is on in the error.
+    pub fn rec_at(&self, record: &csv::ByteRecord) -> Result<Rec, Box<dyn Error>> {
+        self.rec(record).map_err(|e| format!("line {}: {}", line_of(record), e).into())
+    }
+
+    /// Which extra column, counting in extra_names order, goes by one of `names` after normalising.
+    pub fn extra_column(&self, names: &[&str]) -> Option<usize> {
+        self.extras.iter().position(|(name, _)| names.contains(&normalise(name.as_bytes()).as_str()))
+    }
+
+    /// Extra column `i` of one row, as it is written.
+    pub fn extra(&self, record: &csv::ByteRecord, i: usize) -> String {
+        String::from_utf8_lossy(record.get(self.extras[i].1).unwrap_or_default()).trim().to_string()
+    }
+
+    /// The extra columns of one row, as they are written.
+    pub fn extras(&self, record: &csv::ByteRecord) -> Vec<String> {
+        self.extras.iter().map(|(_, at)| String::from_utf8_lossy(record.get(*at).unwrap_or_default()).trim().to_string()).collect()
+    }
+}
+
+/// The extra columns of a whole dump, a column at a time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extras {
+    pub names: Vec<String>,
+    pub columns: Vec<Vec<String>>,
+}
+
+#[allow(dead_code)]
+impl Extras {
+    /// Every row's value of the extra column `name`.
+    pub fn column(&self, name: &str) -> Option<&[String]> {
+        let at = self.names.iter().position(|n| n == name)?;
+        Some(&self.columns[at])
+    }
+
+    /// The extra column `name` read as numbers.
+    pub fn parse<T: std::str::FromStr>(&self, name: &str) -> Option<Result<Vec<T>, T::Err>> {
+        self.column(name).map(|c| c.iter().map(|v| v.parse()).collect())
+    }
+}
+
+/// The records of a csv dump one at a time, in file order, parsing only as far as asked.
+pub fn records(file: &str) -> Result<impl Iterator<Item = Result<Rec, Box<dyn Error>>>, Box<dyn Error>> {
+    let file_path = file.replace("\"","").replace("\\","/");
+    let mut rdr = reader(file_path.trim())?;
+    let schema = Schema::new(rdr.byte_headers()?)?;
+    Ok(rdr.into_byte_records().map(move |r| schema.rec_at(&r?)))
+}
+
+/// Writes records out in the csv schema csvcon reads, header first. Floats are written the
+/// shortest way that reads back to the same bits, so nothing is lost going through a file.
+pub fn write_csv<'r, W: Write>(w: W, recs: impl IntoIterator<Item = &'r Rec>) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(w);
+    for rec in recs {
+        wtr.serialize(rec)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// csvcon for big dumps: the file is cut into byte ranges on line boundaries and rayon parses
+/// them all at once. Records come back in file order. Fields can not have line breaks in them,
+/// which the dumps never do.
+pub fn par_csvcon(file: &str) -> Result<Vec<Rec>, Box<dyn Error>> {
+    par_csvcon_extras(file, false).map(|(recs, _)| recs)
+}
+
+/// par_csvcon that also hands back the dump's extra columns when `extras` is set.
+pub fn par_csvcon_extras(file: &str, extras: bool) -> Result<(Vec<Rec>, Extras), Box<dyn Error>> {
+    let file_path = file.replace("\"","").replace("\\","/");
+    let bytes = std::fs::read(file_path.trim())?;
+    par_parse(&bytes, rayon::current_num_threads() * 4, extras)
+}
+
+fn par_parse(bytes: &[u8], parts: usize, extras: bool) -> Result<(Vec<Rec>, Extras), Box<dyn Error>> {
+    // the metadata lines and the header, the rows start after them
+    let mut head = 0;
+    let body = loop {
+        let end = bytes[head..].iter().position(|&b| b == b'\n').map_or(bytes.len(), |i| head + i + 1);
+        if !bytes[head..].starts_with(b"#") {
+            break end;
+        }
+        head = end;
+    };
+    let schema = Schema::new(csv::Reader::from_reader(&bytes[head..body]).byte_headers()?)?;
+    let names: Vec<String> = if extras { schema.extra_names().map(String::from).collect() } else { Vec::new() };
+    let (all, bytes) = (bytes, &bytes[body..]);
+    let chunks: Vec<(Vec<Rec>, Vec<Vec<String>>)> = line_ranges(bytes, parts).into_par_iter().map(|range| {
+        // the reader counts lines from the start of its range, the lines before it only get counted
+        // once a row turns out to be bad
+        let line = |record: &csv::ByteRecord| all[..body + range.start].iter().filter(|&&b| b == b'\n').count() as u64 + line_of(record);
+        let mut rdr = csv::ReaderBuilder::new().has_headers(false).comment(Some(b'#')).from_reader(&bytes[range.clone()]);
+        let mut record = csv::ByteRecord::new();
+        let mut recs = Vec::new();
+        let mut rows = Vec::new();
+        // errors go back as text, Box<dyn Error> can not cross threads
+        while rdr.read_byte_record(&mut record).map_err(|e| e.to_string())? {
+            recs.push(schema.rec(&record).map_err(|e| format!("line {}: {}", line(&record), e))?);
+            if extras {
+                rows.push(schema.extras(&record));
+            }
+        }
+        Ok((recs, rows))
+    }).collect::<Result<_, String>>()?;
+    let mut columns = vec![Vec::new(); names.len()];
+    for row in chunks.iter().flat_map(|(_, rows)| rows) {
+        for (column, value) in columns.iter_mut().zip(row) {
+            column.push(value.clone());
+        }
+    }
+    Ok((chunks.into_iter().flat_map(|(recs, _)| recs).collect(), Extras { names, columns }))
+}
+
+// about `parts` equal ranges, each moved on to just past the next line break
+fn line_ranges(bytes: &[u8], parts: usize) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for i in 1..=parts.max(1) {
+        let guess = (bytes.len() * i / parts.max(1)).max(start);
+        let end = bytes[guess..].iter().position(|&b| b == b'\n').map_or(bytes.len(), |at| guess + at + 1);
+        if end > start {
+            ranges.push(start..end);
+        }
+        start = end;
+    }
+    ranges
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use super::*;
+
+    // values that do not survive a careless float format, shared with the raw writer's tests
+    pub fn awkward() -> Vec<Rec> {
+        let odd = [1.0/3.0, -0.0, 1e-300, f64::MAX, f64::MIN_POSITIVE, 5e-324, 2.496761960616434e-05, f64::INFINITY];
+        (0..odd.len()).map(|i| {
+            let mut v = [0.0; FIELDS - IDS];
+            for (j, x) in v.iter_mut().enumerate() {
+                *x = odd[(i + j) % odd.len()] * if j % 2 == 0 { 1.0 } else { -7.0 };
+            }
+            Rec::from_values(ParticleIndex(i), ParticleIndex(i + 10), &v)
+        }).collect()
+    }
+
+    #[test]
+    fn write_then_read_is_lossless() {
+        let recs = awkward();
+        let mut out = Vec::new();
+        write_csv(&mut out, &recs).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("p1,p2,time,p1x,p1y,p1z,p1vx,p1vy,p1vz,p1r,p2x,p2y,p2z,p2vx,p2vy,p2vz,p2r,p1m,p2m\n"));
+        let back = par_parse(text.as_bytes(), 3, false).unwrap().0;
+        assert_eq!(bytemuck::cast_slice::<Rec, u8>(&back), bytemuck::cast_slice::<Rec, u8>(&recs));
+    }
+
+    #[test]
+    fn header_decides_the_columns() {
+        // newer dumps: extra columns in between, names written differently
+        let csv = "time,P1,p2,p1.x,p1_y,p1z,mass,p1vx,p1vy,p1vz,p1r,p2x,p2y,p2z,p2vx,p2vy,p2vz,p2r,event type\n\
+                   0.5,1,2,0.1,0.2,0.3,4.5,0.4,0.5,0.6,0.01,0.7,0.8,0.9,1.0,1.1,1.2,0.02,bounce\n\
+                   0.75,3,4,0,0,0,6,0,0,0,0,0,0,0,0,0,0,0,merge\n";
+        let (recs, extras) = par_parse(csv.as_bytes(), 2, true).unwrap();
+        assert_eq!((recs[0].p1, recs[0].p2, recs[0].time, recs[0].p1y, recs[0].p1vx, recs[0].p2r), (ParticleIndex(1), ParticleIndex(2), 0.5, 0.2, 0.4, 0.02));
+        assert_eq!(extras.names, ["mass", "event type"]);
+        assert_eq!(extras.column("event type").unwrap(), ["bounce", "merge"]);
+        assert_eq!(extras.parse::<f64>("mass").unwrap().unwrap(), [4.5, 6.0]);
+        assert!(extras.column("spin").is_none());
+        assert!(par_parse(csv.as_bytes(), 2, false).unwrap().1.columns.is_empty());
+
+        // metadata lines ahead of the header, and the odd comment in between rows
+        let recs = par_parse(b"# step=0.5\n# particles=2\np1,p2,time\n1,2,0.5\n# half way\n1,2,0.75\n", 2, false).unwrap().0;
+        assert_eq!(recs.iter().map(|r| r.time).collect::<Vec<_>>(), [0.5, 0.75]);
+        let csv_path = std::env::temp_dir().join(format!("queue_test_{}_meta.csv", std::process::id())).to_string_lossy().into_owned();
+        std::fs::write(&csv_path, "# step=0.5\np1,p2,time\n1,2,0.5\n1,2,x\n").unwrap();
+        assert!(csvcon(&csv_path).unwrap_err().to_string().starts_with("line 4: "));
+
+        // older dumps with just the pair and the time
+        let recs = par_parse(b"p1,p2,time\n1.0,2,0.5\n", 1, false).unwrap().0;
+        assert_eq!((recs[0].p1, recs[0].time, recs[0].p1x, recs[0].p2r), (ParticleIndex(1), 0.5, 0.0, 0.0));
+
+        // 2-D dumps
+        let headers = csv::ByteRecord::from(vec!["p1", "p2", "time", "p1x", "p1y", "p1vx", "p1vy", "p1r", "p2x", "p2y", "p2vx", "p2vy", "p2r"]);
+        let schema = Schema::new(&headers).unwrap();
+        assert!(schema.is_2d());
+        let rec = schema.rec(&csv::ByteRecord::from(vec!["1", "2", "0.5", "0.1", "0.2", "0.3", "0.4", "0.01", "0.5", "0.6", "0.7", "0.8", "0.02"])).unwrap();
+        assert_eq!((rec.p1vx, rec.p1z, rec.p1vz, rec.p2vy, rec.p2r), (0.3, 0.0, 0.0, 0.8, 0.02));
+        assert!(!Schema::new(&csv::ByteRecord::from(COLUMNS.to_vec())).unwrap().is_2d());
+
+        assert!(Schema::new(&csv::ByteRecord::from(vec!["p1", "time"])).unwrap_err().to_string().contains("`p2`"));
+        assert!(Schema::new(&csv::ByteRecord::from(vec!["p1", "p2", "time", "p1.x", "p1x"])).is_err());
+        let err = par_parse(b"p1,p2,time\n1,2\n", 1, false).unwrap_err();
+        assert!(err.to_string().contains("time"), "{}", err);
+    }
+
+    #[test]
+    fn masses_from_columns_or_density() {
+        let csv = "p1,p2,time,p1r,p2r,p1.mass,p2rho\n1,2,0.5,0.5,2,3.5,0.25\n";
+        let mut rec = par_parse(csv.as_bytes(), 1, false).unwrap().0[0];
+        assert_eq!(rec.p1m, 3.5);
+        assert_eq!(rec.p2m, Particle::mass_from_radius(2.0, 0.25));
+        rec.resolve_masses(10.0);
+        assert_eq!((rec.p1m, rec.p2m), (3.5, Particle::mass_from_radius(2.0, 0.25)));
+
+        // no masses: the dataset's density, or the default one if nobody says
+        let mut rec = par_parse(b"p1,p2,time,p1r,p2r\n1,2,0.5,0.5,2\n", 1, false).unwrap().0[0];
+        assert!(rec.p1m.is_nan() && rec.p2m.is_nan());
+        assert_eq!(rec.p2().m, Particle::mass_from_radius(2.0, particle::DEFAULT_DENSITY));
+        rec.resolve_masses(3.0);
+        assert_eq!(rec.p1().m, Particle::mass_from_radius(0.5, 3.0));
+
+        // particles are where they are at the event
+        assert_eq!((rec.p1().t, rec.p2().t), (0.5, 0.5));
+    }
+
+    #[test]
+    fn parallel_parse_keeps_file_order() {
+        let mut csv = String::from("p1,p2,time,p1x,p1y,p1z,p1vx,p1vy,p1vz,p1r,p2x,p2y,p2z,p2vx,p2vy,p2vz,p2r\n");
+        for i in 0..500 {
+            csv += &format!("{},{},{},0.1,0.2,0.3,0.4,0.5,0.6,0.001,0.7,0.8,0.9,1.0,1.1,1.2,0.002\n", i % 17, i % 5, (i * 7919 % 500) as f64 / 100.0);
+        }
+        let whole: Vec<Rec> = csv::Reader::from_reader(csv.as_bytes()).deserialize().collect::<Result<_, _>>().unwrap();
+        for parts in [1, 3, 64, 10_000] {
+            let recs = par_parse(csv.as_bytes(), parts, false).unwrap().0;
+            assert_eq!(recs.len(), 500);
+            assert!(recs.iter().zip(&whole).all(|(a, b)| a.time == b.time && a.p1 == b.p1 && a.p2r == b.p2r), "{} parts", parts);
+        }
+        // a last line without a line break, and columns in another order
+        let swapped = "p2,p1,time,p1x,p1y,p1z,p1vx,p1vy,p1vz,p1r,p2x,p2y,p2z,p2vx,p2vy,p2vz,p2r\n2,1,0.5,0,0,0,0,0,0,0,0,0,0,0,0,0,0";
+        let recs = par_parse(swapped.as_bytes(), 4, false).unwrap().0;
+        assert_eq!((recs[0].p1, recs[0].p2), (ParticleIndex(1), ParticleIndex(2)));
+        assert!(par_parse(b"p1,p2,time\n1,2,x\n", 2, false).is_err());
+
+        // a bad row far into the file names its line in the file, not in the range it was parsed in
+        let mut bad = String::from("# step=1\n# comment\n") + &csv;
+        bad += "3,4,oops,0,0,0,0,0,0,0,0,0,0,0,0,0,0\n";
+        for parts in [1, 3, 64] {
+            let err = par_parse(bad.as_bytes(), parts, false).unwrap_err().to_string();
+            assert!(err.starts_with("line 504: ") && err.contains("oops"), "{} parts: {}", parts, err);
+        }
+    }
+
+    #[test]
+    fn ids_are_whole_numbers() {
+        let rec = par_parse(b"p1,p2,time\n7,3.0,0.5\n", 1, false).unwrap().0[0];
+        assert_eq!((rec.p1, rec.p2), (ParticleIndex(7), ParticleIndex(3)));
+        assert_eq!(rec.pair(), Rec { p1: rec.p2, p2: rec.p1, ..rec }.pair());
+        for (row, why) in [("1.5,2,0.5", "not a whole number"), ("-1,2,0.5", "negative"), ("1,5e30,0.5", "too large"), ("1,x,0.5", "not a number")] {
+            let err = par_parse(format!("p1,p2,time\n{}\n", row).as_bytes(), 1, false).unwrap_err().to_string();
+            assert!(err.contains(why), "{}: {}", row, err);
+        }
+        // serde reads the ids the same way
+        let recs: Result<Vec<Rec>, _> = csv::Reader::from_reader(&b"p1,p2,time\n1.5,2,0.5\n"[..]).deserialize().collect();
+        assert!(recs.unwrap_err().to_string().contains("not a whole number"));
+    }
+
+    #[test]
+    fn ranges_end_on_line_breaks() {
+        let bytes = b"aa\nbbbb\nc\n\ndddddd\ne";
+        for parts in 1..30 {
+            let ranges = line_ranges(bytes, parts);
+            assert_eq!(ranges.first().unwrap().start, 0);
+            assert_eq!(ranges.last().unwrap().end, bytes.len());
+            assert!(ranges.windows(2).all(|w| w[0].end == w[1].start && bytes[w[0].end - 1] == b'\n'));
+        }
+        assert!(line_ranges(b"", 4).is_empty());
+    }
+}
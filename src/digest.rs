@@ -0,0 +1,219 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crossbeam::utils::CachePadded;
+
+use crate::particle::{Pair, Particle};
+
+// splitmix64's finalizer, folding one more word into a hash. Written out instead of using std's
+// hashers so a digest means the same thing between builds and machines.
+fn mix(h: u64, x: u64) -> u64 {
+    let mut z = (h ^ x).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Hash of one processed event: the pair, when it happened and the next time process_collision
+/// came up with for it, so two runs only agree if they did the same collisions with the same outcome.
+pub fn event_hash(pair: Pair, time: f64, next_time: f64) -> u64 {
+    [pair.first().0 as u64, pair.second().0 as u64, time.to_bits(), next_time.to_bits()].into_iter().fold(0, mix)
+}
+
+/// Hash of the particle states at the end of a run, in the order given.
+#[allow(dead_code)]
+pub fn particles_hash(particles: &[Particle]) -> u64 {
+    particles.iter().flat_map(|p| {
+        [p.p.x(), p.p.y(), p.p.z(), p.v.x(), p.v.y(), p.v.z(), p.r, p.m, p.t].map(f64::to_bits)
+    }).fold(0, mix)
+}
+
+/// Events processed in one global step. The hash is the wrapping sum of the event hashes, so
+/// it does not care in which order or on which thread they were processed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StepDigest {
+    pub count: u64,
+    pub hash: u64,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    count: AtomicU64,
+    hash: AtomicU64,
+}
+
+/// What a driver hands a step's events to while it runs, from as many threads as it likes. Every
+/// rayon worker adds to a slot of its own on a cache line of its own, so the threads never fight
+/// over one counter, and the slots only get summed up in finish once the step is done. Threads
+/// outside the pool share one last slot.
+#[derive(Debug)]
+pub struct StepTally {
+    slots: Box<[CachePadded<Slot>]>,
+}
+
+impl Default for StepTally {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StepTally {
+    pub fn new() -> Self {
+        StepTally { slots: (0..rayon::current_num_threads() + 1).map(|_| CachePadded::new(Slot::default())).collect() }
+    }
+
+    pub fn add(&self, event_hash: u64) {
+        let shared = self.slots.len() - 1;
+        let slot = &self.slots[rayon::current_thread_index().map_or(shared, |i| i.min(shared))];
+        slot.count.fetch_add(1, Ordering::Relaxed);
+        slot.hash.fetch_add(event_hash, Ordering::Relaxed);
+    }
+
+    pub fn finish(self) -> StepDigest {
+        self.slots.iter().fold(StepDigest::default(), |d, s| StepDigest {
+            count: d.count + s.count.load(Ordering::Relaxed),
+            hash: d.hash.wrapping_add(s.hash.load(Ordering::Relaxed)),
+        })
+    }
+}
+
+/// Everything a run did, step by step. Two runs over the same dump should come out equal
+/// whatever queue or thread count they used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Digest {
+    pub steps: Vec<StepDigest>,
+    /// Hash of the final particle states, for runs that keep track of them. The drivers here do
+    /// not, every event starts from the particles its record has, so for them this stays None.
+    pub particles: Option<u64>,
+}
+
+impl Digest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_step(&mut self, tally: StepTally) {
+        self.steps.push(tally.finish());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.steps.iter().map(|s| s.count).sum()
+    }
+
+    /// Steps nothing happened in leave the hash alone, so it does not matter how many empty steps
+    /// a run carried along at the end.
+    pub fn hash(&self) -> u64 {
+        self.steps.iter().filter(|s| s.count > 0).fold(0, |h, s| mix(h, s.hash))
+    }
+
+    /// The first global step `other` did differently, or None if the runs agree step for step.
+    /// A differing number of steps counts from the first step only one of them has.
+    pub fn first_difference(&self, other: &Digest) -> Option<usize> {
+        (0..self.steps.len().max(other.steps.len())).find(|&i| self.steps.get(i) != other.steps.get(i))
+    }
+}
+
+impl fmt::Display for Digest {
+    /// One line with the totals, `{:#}` adds a line per global step.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} events, hash {:016x}", self.count(), self.hash())?;
+        if let Some(particles) = self.particles {
+            write!(f, ", particles {:016x}", particles)?;
+        }
+        if f.alternate() {
+            for (i, s) in self.steps.iter().enumerate() {
+                write!(f, "\n  step {}: {} events, hash {:016x}", i, s.count, s.hash)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rayon::prelude::*;
+
+    use crate::particle::ParticleIndex;
+    use crate::vectors::Vector;
+
+    use super::*;
+
+    fn pair(a: usize, b: usize) -> Pair {
+        Pair::new(ParticleIndex(a), ParticleIndex(b))
+    }
+
+    fn run(order: &[usize]) -> Digest {
+        let mut digest = Digest::new();
+        for step in 0..3 {
+            let tally = StepTally::new();
+            order.par_iter().for_each(|&i| tally.add(event_hash(pair(i, i + 1), step as f64 + i as f64 / 10.0, 0.5)));
+            digest.push_step(tally);
+        }
+        digest
+    }
+
+    #[test]
+    fn order_does_not_matter() {
+        let forward: Vec<usize> = (0..10).collect();
+        let backward: Vec<usize> = (0..10).rev().collect();
+        let a = run(&forward);
+        assert_eq!(a, run(&backward));
+        assert_eq!(a.count(), 30);
+        assert_eq!(a.first_difference(&run(&backward)), None);
+        assert_eq!(format!("{:#}", a).lines().count(), 4);
+    }
+
+    #[test]
+    fn tallies_from_any_thread() {
+        let hashes: Vec<u64> = (0..1000).map(|i| event_hash(pair(i, i + 1), i as f64, 0.5)).collect();
+        let expected = StepDigest { count: 1000, hash: hashes.iter().fold(0, |a: u64, &h| a.wrapping_add(h)) };
+
+        let tally = StepTally::new();
+        hashes.par_iter().for_each(|&h| tally.add(h));
+        assert_eq!(tally.finish(), expected);
+
+        // plain threads and a pool with more threads than the tally has slots for share the last one
+        let tally = StepTally::new();
+        std::thread::scope(|s| {
+            for chunk in hashes[..500].chunks(100) {
+                s.spawn(|| chunk.iter().for_each(|&h| tally.add(h)));
+            }
+        });
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(rayon::current_num_threads() + 3).build().unwrap();
+        pool.install(|| hashes[500..].par_iter().for_each(|&h| tally.add(h)));
+        assert_eq!(tally.finish(), expected);
+    }
+
+    #[test]
+    fn finds_the_step_that_differs() {
+        let a = run(&(0..10).collect::<Vec<_>>());
+        let mut b = a.clone();
+        b.steps[1].hash ^= 1;
+        assert_ne!(a.hash(), b.hash());
+        assert_eq!(a.first_difference(&b), Some(1));
+
+        let mut short = a.clone();
+        short.steps.pop();
+        assert_eq!(a.first_difference(&short), Some(2));
+
+        let mut padded = a.clone();
+        padded.push_step(StepTally::new());
+        assert_eq!(a.hash(), padded.hash());
+        assert_eq!(a.first_difference(&padded), Some(3));
+
+        assert_eq!(event_hash(pair(1, 2), 0.5, 1.0), event_hash(pair(2, 1), 0.5, 1.0));
+        assert_ne!(event_hash(pair(1, 2), 0.5, 1.0), event_hash(pair(1, 3), 0.5, 1.0));
+        assert_ne!(event_hash(pair(1, 2), 0.5, 1.0), event_hash(pair(1, 2), 0.5, 1.5));
+    }
+
+    #[test]
+    fn particle_states_change_the_hash() {
+        let p = Particle::new(Vector::new(1.0, 2.0, 3.0), Vector::new(0.0, 1.0, 0.0), 0.5, 1.0);
+        let mut q = p;
+        q.v = Vector::new(0.0, -1.0, 0.0);
+        assert_eq!(particles_hash(&[p, q]), particles_hash(&[p, q]));
+        assert_ne!(particles_hash(&[p, q]), particles_hash(&[q, p]));
+        assert_ne!(particles_hash(&[p]), particles_hash(&[q]));
+    }
+}
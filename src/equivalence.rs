@@ -0,0 +1,254 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use rayon::prelude::*;
+
+use crate::adapters::Locked;
+use crate::parallelbucketqueue::ParBqueue;
+use crate::partition::Partition;
+use crate::particle::Pair;
+use crate::sequentialbucketqueue::Bqueue;
+use crate::uniquequeue::{DuplicatePolicy, UniqueQueue};
+use crate::{KeyVal, ParallelPriorityQueue, SeqentialPriorityQueue};
+
+/// One processed event, as much of it as a report needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Seen {
+    pub step: usize,
+    pub index: usize,
+    pub time: f64,
+    pub pair: Pair,
+}
+
+impl Seen {
+    fn new(step: usize, k: &KeyVal) -> Self {
+        Seen { step, index: k.index, time: k.key.0, pair: k.id }
+    }
+
+    fn at(&self) -> (usize, usize) {
+        (self.step, self.index)
+    }
+}
+
+impl fmt::Display for Seen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "step {} #{} t={:e} pair {}", self.step, self.index, self.time, self.pair)
+    }
+}
+
+/// Everything a queue handed out, one entry per pop or per bulk_pop.
+pub type Trace = Vec<Vec<Seen>>;
+
+/// Where two traces first disagree. `expected` and `found` are the conflicting events, for a bucket
+/// that is the ones only the reference or only the other queue had in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub bucketed: bool,
+    pub at: usize,
+    pub expected: Vec<Seen>,
+    pub found: Vec<Seen>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "first divergence at {} {}", if self.bucketed { "bucket" } else { "event" }, self.at)?;
+        for e in &self.expected {
+            writeln!(f, "  expected {}", e)?;
+        }
+        for e in &self.found {
+            writeln!(f, "  found    {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+// same successor rule as the drivers in main
+fn successor<'a>(data: &'a Partition, step: usize, elem: &KeyVal) -> Option<&'a KeyVal> {
+    data.step(step)[elem.index+1..].iter().find(|k| k.id == elem.id)
+}
+
+/// Replays `data` the way time_seqential does and records every pop.
+pub fn trace_sequential<'a, PQ: SeqentialPriorityQueue<'a, KeyVal>>(data: &'a Partition, heap: &mut PQ) -> Trace {
+    let mut trace = Vec::new();
+    for (i, step) in data.steps().enumerate() {
+        for &h in data.heads(i) {
+            heap.push(&step[h]);
+        }
+        while let Some(elem) = heap.pop() {
+            trace.push(vec![Seen::new(i, elem)]);
+            if let Some(next) = successor(data, i, elem) {
+                heap.push(next);
+            }
+        }
+    }
+    trace
+}
+
+/// Replays `data` the way time_parallel does and records every bucket. This is bulk_process taken
+/// apart, so the bucket can be looked at before its successors go back in.
+pub fn trace_parallel<'a, PQ: ParallelPriorityQueue<'a, KeyVal>>(data: &'a Partition, heap: &mut PQ) -> Trace {
+    let mut trace = Vec::new();
+    let mut bucket = Vec::new();
+    for (i, step) in data.steps().enumerate() {
+        heap.bulk_push(data.heads(i).par_iter().map(|&h| &step[h]));
+        while !heap.is_empty() {
+            heap.bulk_pop_into(&mut bucket);
+            trace.push(bucket.iter().map(|e| Seen::new(i, e)).collect());
+            let next: Vec<&KeyVal> = bucket.par_iter().filter_map(|e| successor(data, i, e)).collect();
+            heap.bulk_push(next.into_par_iter());
+        }
+    }
+    trace
+}
+
+/// Compares two traces event by event.
+pub fn first_event_divergence(reference: &Trace, found: &Trace) -> Option<Divergence> {
+    let reference: Vec<Seen> = reference.iter().flatten().copied().collect();
+    let found: Vec<Seen> = found.iter().flatten().copied().collect();
+    let at = (0..reference.len().max(found.len())).find(|&i| reference.get(i).map(Seen::at) != found.get(i).map(Seen::at))?;
+    Some(Divergence {
+        bucketed: false,
+        at,
+        expected: reference.get(at).copied().into_iter().collect(),
+        found: found.get(at).copied().into_iter().collect(),
+    })
+}
+
+// merges everything handed out in a row for the same step and bucket, sorted so only membership counts
+fn buckets(trace: &Trace, width: f64) -> Vec<Vec<Seen>> {
+    let mut out: Vec<Vec<Seen>> = Vec::new();
+    let bucket_of = |e: &Seen| (e.step, (e.time/width).floor() as i64);
+    for e in trace.iter().flatten() {
+        match out.last_mut() {
+            Some(last) if bucket_of(&last[0]) == bucket_of(e) => last.push(*e),
+            _ => out.push(vec![*e]),
+        }
+    }
+    for bucket in out.iter_mut() {
+        bucket.sort_by_key(Seen::at);
+    }
+    out
+}
+
+/// Compares two traces bucket by bucket, for queues that only promise which bucket comes next.
+pub fn first_bucket_divergence(reference: &Trace, found: &Trace, width: f64) -> Option<Divergence> {
+    let reference = buckets(reference, width);
+    let found = buckets(found, width);
+    let empty = Vec::new();
+    let at = (0..reference.len().max(found.len())).find(|&i| {
+        reference.get(i).unwrap_or(&empty).iter().map(Seen::at).ne(found.get(i).unwrap_or(&empty).iter().map(Seen::at))
+    })?;
+    let (expected, found) = (reference.get(at).unwrap_or(&empty), found.get(at).unwrap_or(&empty));
+    Some(Divergence {
+        bucketed: true,
+        at,
+        expected: expected.iter().filter(|e| !found.iter().any(|f| f.at() == e.at())).copied().collect(),
+        found: found.iter().filter(|f| !expected.iter().any(|e| e.at() == f.at())).copied().collect(),
+    })
+}
+
+/// Replays `data` through every queue and compares each against the BinaryHeap: exactly for the
+/// sequential queues, bucket by bucket for the parallel ones. Prints what it finds and returns
+/// whether all of them agreed.
+pub fn report(data: &Partition, bucketnum: usize, width: f64) -> bool {
+    let policy = DuplicatePolicy::KeepEarliest;
+    let reference = trace_sequential(data, &mut UniqueQueue::new(BinaryHeap::<Reverse<&KeyVal>>::new(), policy));
+    let count: usize = reference.iter().map(Vec::len).sum();
+    println!("BinaryHeap: {} events processed (reference)", count);
+
+    let results = [
+        ("Bqueue", first_event_divergence(&reference, &trace_sequential(data, &mut UniqueQueue::new(Bqueue::new(bucketnum, width), policy)))),
+        ("ParBqueue", first_bucket_divergence(&reference, &trace_parallel(data, &mut UniqueQueue::new(ParBqueue::new(bucketnum, width), policy)), width)),
+        ("LockingBinaryHeap", first_bucket_divergence(&reference, &trace_parallel(data, &mut UniqueQueue::new(Locked::new(BinaryHeap::<Reverse<&KeyVal>>::new(), width), policy)), width)),
+    ];
+    let mut agreed = true;
+    for (name, divergence) in results {
+        match divergence {
+            None => println!("{}: matches", name),
+            Some(d) => {
+                agreed = false;
+                print!("{}: {}", name, d);
+            }
+        }
+    }
+    agreed
+}
+
+#[cfg(test)]
+mod tests {
+
+    use ordered_float::OrderedFloat;
+
+    use crate::csvreader::Rec;
+    use crate::particle::{Particle, ParticleIndex};
+    use crate::vectors::Vector;
+
+    use super::*;
+
+    fn kv(p1: usize, p2: usize, time: f64, index: usize) -> KeyVal {
+        let p = Particle::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, 0.0), 1.0, 1.0);
+        let val = Rec::new(ParticleIndex(p1), ParticleIndex(p2), &p, &p, time);
+        KeyVal { key: OrderedFloat(time), val, id: val.pair(), index, dt: f64::INFINITY }
+    }
+
+    fn steps() -> Partition {
+        // four pairs at every time, and pairs coming back within a step
+        (0..4).map(|s| {
+            (0..80).map(|i| kv(i % 9, 9 + i % 4, s as f64 + (i / 4) as f64 / 20.0, i)).collect()
+        }).collect::<Vec<Vec<KeyVal>>>().into()
+    }
+
+    #[test]
+    fn every_queue_agrees() {
+        let data = steps();
+        let mut heap = UniqueQueue::new(BinaryHeap::<Reverse<&KeyVal>>::new(), DuplicatePolicy::KeepEarliest);
+        let reference = trace_sequential(&data, &mut heap);
+        let bq = trace_sequential(&data, &mut UniqueQueue::new(Bqueue::new(10, 0.5), DuplicatePolicy::KeepEarliest));
+        assert_eq!(first_event_divergence(&reference, &bq), None);
+        let pbq = trace_parallel(&data, &mut UniqueQueue::new(ParBqueue::new(10, 0.5), DuplicatePolicy::KeepEarliest));
+        assert_eq!(first_bucket_divergence(&reference, &pbq, 0.5), None);
+        assert!(report(&data, 10, 0.5));
+    }
+
+    #[test]
+    fn pairs_out_of_time_order_lose_nothing() {
+        // the first pair's second event in the file happens before its first one
+        let data: Partition = vec![vec![kv(1, 2, 0.5, 0), kv(1, 2, 0.2, 1), kv(3, 4, 0.3, 2), kv(1, 2, 0.1, 3)]].into();
+        let reference = trace_sequential(&data, &mut UniqueQueue::new(BinaryHeap::<Reverse<&KeyVal>>::new(), DuplicatePolicy::KeepEarliest));
+        let order: Vec<usize> = reference.iter().flatten().map(|e| e.index).collect();
+        assert_eq!(order, [2, 0, 1, 3]);
+        let pbq = trace_parallel(&data, &mut UniqueQueue::new(ParBqueue::new(10, 0.5), DuplicatePolicy::KeepEarliest));
+        assert_eq!(pbq.iter().flatten().count(), 4);
+        assert!(report(&data, 10, 0.5));
+    }
+
+    #[test]
+    fn reports_first_difference() {
+        let data = steps();
+        let mut heap = UniqueQueue::new(BinaryHeap::<Reverse<&KeyVal>>::new(), DuplicatePolicy::KeepEarliest);
+        let reference = trace_sequential(&data, &mut heap);
+
+        let mut swapped = reference.clone();
+        swapped.swap(5, 6);
+        let d = first_event_divergence(&reference, &swapped).unwrap();
+        assert_eq!(d.at, 5);
+        assert_eq!(d.expected, reference[5]);
+        assert_eq!(d.found, reference[6]);
+
+        // moving an event into the next bucket shows up as missing from one and extra in the other
+        let mut moved = reference.clone();
+        let late = moved.iter().position(|b| b[0].time >= 0.5).unwrap();
+        let e = moved.remove(late - 1);
+        moved.insert(late, e.clone());
+        let d = first_bucket_divergence(&reference, &moved, 0.5).unwrap();
+        assert_eq!(d.at, 0);
+        assert_eq!(d.expected, e);
+        assert!(d.found.is_empty());
+
+        let mut short = reference.clone();
+        let last = short.pop().unwrap();
+        let d = first_event_divergence(&reference, &short).unwrap();
+        assert_eq!(d.expected, last);
+        assert!(d.found.is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+use rayon::prelude::*;
+
+use crate::{sequentialbucketqueue::HasKey, ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
+
+/// How elements that landed in the same finest bucket come back out.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelOrder {
+    Exact,   // smallest first, ties in whatever order PartialOrd gives
+    Relaxed, // any order, same guarantee as Bqueue gives within a bucket
+}
+
+/// Bucket queue whose buckets get geometrically wider the further they are from the current time,
+/// so contact events a few microseconds apart and gravity events an orbit apart can share one queue.
+///
+/// Keys are cut into ticks of `finest` width. Level 0 holds the ticks equal to the current one,
+/// level l holds the ticks whose highest base-2^`bits` digit that differs from the current tick is
+/// digit l-1, so every level covers 2^`bits` times more time than the one before it. When level 0
+/// runs dry the first non-empty level is split back down around its smallest key (like a radix heap),
+/// which means nothing is ever preallocated for the horizon and keys only need to be no smaller than
+/// the last one popped.
+#[derive(Debug)]
+pub struct LogBqueue<T> {
+    finest: f64,
+    bits: u32,
+    order: LevelOrder,
+    base: u64,           // tick everything is measured from, only ever moves forward
+    levels: Vec<Vec<T>>, // level 0 is kept sorted largest first in Exact mode so pop can take the back
+    len: usize,
+}
+
+#[allow(dead_code)]
+impl<'a, T: PartialOrd + HasKey> LogBqueue<&'a T> {
+    /// `finest` is the width of level 0, each further level is 2^`bits` times wider.
+    pub fn new(finest: f64, bits: u32, order: LevelOrder) -> Self {
+        let bits = bits.clamp(1, 32);
+        let levelnum = 1 + 64_usize.div_ceil(bits as usize);
+        LogBqueue {
+            finest,
+            bits,
+            order,
+            base: 0,
+            levels: (0..levelnum).map(|_| Vec::new()).collect(),
+            len: 0,
+        }
+    }
+
+    fn tick(&self, elem: &T) -> u64 {
+        (elem.key().0 / self.finest).floor().max(0.0) as u64
+    }
+
+    // events scheduled before the current tick are not supposed to happen, they go to level 0
+    fn level(&self, tick: u64) -> usize {
+        if tick <= self.base {
+            return 0;
+        }
+        let msb = 64 - (tick ^ self.base).leading_zeros();
+        (msb as usize).div_ceil(self.bits as usize)
+    }
+
+    pub fn push(&mut self, elem: &'a T) {
+        let level = self.level(self.tick(elem));
+        if level == 0 && self.order == LevelOrder::Exact {
+            let front = &mut self.levels[0];
+            let at = front.partition_point(|e| *e > elem);
+            front.insert(at, elem);
+        } else {
+            self.levels[level].push(elem);
+        }
+        self.len += 1;
+    }
+
+    /// Makes level 0 non-empty again by moving the base up to the smallest tick left and splitting
+    /// the first non-empty level around it. Levels above it keep their elements where they are.
+    fn refill(&mut self) {
+        if !self.levels[0].is_empty() || self.len == 0 {
+            return;
+        }
+        let level = self.levels.iter().position(|l| !l.is_empty()).unwrap();
+        let moving = std::mem::take(&mut self.levels[level]);
+        self.base = moving.iter().map(|e| self.tick(e)).min().unwrap();
+        for elem in moving {
+            let level = self.level(self.tick(elem));
+            self.levels[level].push(elem);
+        }
+        if self.order == LevelOrder::Exact {
+            // everything lands unsorted and gets sorted once, inserting each one where push would is
+            // quadratic. Reversed first so equal elements still come out in the order they went in.
+            let front = &mut self.levels[0];
+            front.reverse();
+            front.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<&'a T> {
+        self.refill();
+        let y = self.levels[0].pop();
+        if y.is_some() {
+            self.len -= 1;
+        }
+        y
+    }
+
+    /// Does not refill, so when level 0 is empty this scans the first non-empty level for its minimum.
+    pub fn peek(&self) -> Option<&'a T> {
+        if let Some(e) = self.levels[0].last() {
+            return Some(*e);
+        }
+        let level = self.levels.iter().find(|l| !l.is_empty())?;
+        level.iter().copied().reduce(|a, b| if b < a { b } else { a })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Empties every level without giving back their memory, and starts counting ticks from 0 again.
+    pub fn clear(&mut self) {
+        for level in self.levels.iter_mut() {
+            level.clear();
+        }
+        self.base = 0;
+        self.len = 0;
+    }
+
+    /// Removes every element with a key before `time`, a tick at a time.
+    pub fn drain_until(&mut self, time: f64) -> Vec<&'a T> {
+        let mut ret = Vec::new();
+        loop {
+            self.refill();
+            if self.levels[0].is_empty() || self.current() >= time {
+                break;
+            }
+            // unless time falls inside this tick the whole tick goes
+            let whole = (self.base + 1) as f64 * self.finest <= time;
+            let front = &mut self.levels[0];
+            let before = ret.len();
+            if whole {
+                ret.append(front);
+            } else {
+                ret.extend(front.iter().filter(|e| e.key().0 < time));
+                front.retain(|e| e.key().0 >= time);
+            }
+            self.len -= ret.len() - before;
+            if !whole {
+                break;
+            }
+        }
+        ret
+    }
+
+    /// The key where the current tick starts, nothing smaller should be pushed any more.
+    pub fn current(&self) -> f64 {
+        self.base as f64 * self.finest
+    }
+
+    pub fn bulk_process<F: Fn(&'a T) -> Option<&'a T> + Sync + Send>(&mut self, f: F) where T: Sync {
+        let bucket: Vec<&'a T> = self.bulk_pop().collect();
+        let mapped: Vec<&'a T> = bucket.into_par_iter().filter_map(f).collect();
+        self.bulk_push(mapped.into_par_iter());
+    }
+
+    pub fn bulk_push<I: ParallelIterator<Item = &'a T>>(&mut self, es: I) where T: Sync {
+        let elems: Vec<&'a T> = es.collect();
+        for elem in elems {
+            self.push(elem);
+        }
+    }
+
+    /// Pops the whole current tick. In Exact mode the iterator yields it smallest first.
+    pub fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a T> where T: Sync {
+        self.refill();
+        let mut bucket = std::mem::take(&mut self.levels[0]);
+        self.len -= bucket.len();
+        bucket.reverse();
+        bucket.into_par_iter()
+    }
+}
+
+impl<'a, E: Ord + HasKey> SeqentialPriorityQueue<'a, E> for LogBqueue<&'a E> {
+    fn push(&mut self, e: &'a E) {
+        LogBqueue::push(self, e);
+    }
+    fn pop(&mut self) -> Option<&'a E> {
+        LogBqueue::pop(self)
+    }
+    fn peek(&self) -> Option<&'a E> {
+        LogBqueue::peek(self)
+    }
+    fn is_empty(&self) -> bool {
+        LogBqueue::is_empty(self)
+    }
+    fn len(&self) -> usize {
+        LogBqueue::len(self)
+    }
+    fn clear(&mut self) {
+        LogBqueue::clear(self)
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        LogBqueue::drain_until(self, time)
+    }
+    fn pop_order(&self) -> PopOrder {
+        PopOrder::Bucketed { width: self.finest, sorted: self.order == LevelOrder::Exact }
+    }
+}
+
+impl<'a, E: Ord + HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for LogBqueue<&'a E> {
+    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
+        LogBqueue::bulk_process(self, f);
+    }
+    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
+        LogBqueue::bulk_push(self, es);
+    }
+    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
+        LogBqueue::bulk_pop(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let mut rng = rand::thread_rng();
+        // contact style events 1e-6 apart mixed with gravity style ones out to 1e3
+        let mut vector: Vec<f64> = (0..2000).map(|_| rng.gen_range(0.0..1e-3)).collect();
+        vector.extend((0..500).map(|_| rng.gen_range(0.0..1e3)));
+        let mut sortvec = vector.clone();
+        sortvec.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        for bits in [1, 3] {
+            let mut heap1: LogBqueue<&f64> = LogBqueue::new(1e-6, bits, LevelOrder::Exact);
+            assert!(heap1.is_empty());
+            for v in &vector {
+                heap1.push(v);
+            }
+            assert_eq!(heap1.len(), vector.len());
+            for min in &sortvec {
+                assert_eq!(heap1.peek(), Some(min));
+                assert_eq!(heap1.pop(), Some(min));
+            }
+            assert!(heap1.is_empty());
+            assert_eq!(heap1.pop(), None);
+        }
+
+        let mut heap1: LogBqueue<&f64> = LogBqueue::new(1e-6, 2, LevelOrder::Relaxed);
+        for v in &vector {
+            heap1.push(v);
+        }
+        for min in &sortvec {
+            assert_eq!((heap1.pop().unwrap() / 1e-6).floor(), (min / 1e-6).floor());
+        }
+        assert!(heap1.is_empty());
+    }
+
+    #[test]
+    fn bulk_pop_is_one_tick() {
+        let values: Vec<f64> = (0..300).map(|i| i as f64 * 0.25).collect();
+        let mut heap1: LogBqueue<&f64> = LogBqueue::new(1.0, 2, LevelOrder::Exact);
+        heap1.bulk_push(values.par_iter());
+        let mut tick = 0.0;
+        while !heap1.is_empty() {
+            let bucket: Vec<&f64> = heap1.bulk_pop().collect();
+            assert_eq!(bucket.len(), 4);
+            for (i, v) in bucket.iter().enumerate() {
+                assert_eq!(**v, tick + i as f64 * 0.25);
+            }
+            // popping single elements in between does not lose the tick
+            if tick == 10.0 {
+                assert_eq!(heap1.peek(), Some(&values[44]));
+                assert_eq!(heap1.pop(), Some(&values[44]));
+                assert_eq!(heap1.pop(), Some(&values[45]));
+                heap1.push(&values[44]);
+                heap1.push(&values[45]);
+            }
+            tick += 1.0;
+        }
+    }
+}
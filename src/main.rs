@@ -1,36 +1,62 @@
-use std::collections::HashSet;
-use std::sync::Mutex;
 pub(crate) use std::{collections::BinaryHeap, f64::consts::PI, time::Instant}; 
 use collisionhandling::process_collision;
 // use parwithmutex::HasKey;
 use rayon::prelude::*;
 
 mod csvreader;
+mod rawreader;
+mod bindump;
+mod stream;
+mod metadata;
+mod validate;
+mod partition;
 mod sequentialbucketqueue;
 mod parallelbucketqueue;
+mod parwithmutex;
+mod logbucketqueue;
+mod timingwheel;
+mod blocktimestep;
+mod uniquequeue;
+mod adapters;
+mod arena;
+mod equivalence;
+mod digest;
 mod collisionhandling;
 mod vectors;
 mod particle;
+mod tuning;
+#[cfg(test)]
+mod conformance;
 //mod tryingmybesthere;
 // mod parwithmutex;
 use ordered_float::OrderedFloat;
 use sequentialbucketqueue::HasKey;
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
 use std::time::Duration;
+use digest::{Digest, StepTally};
 
-const DELTA:f64 = 2.0*PI*1E-4 - 2.0*PI*1E-5;
+const DELTA:f64 = 2.0*PI*1E-4 - 2.0*PI*1E-5; // only when neither the header nor the events say
+
+// global steps a dump gets cut into when its header does not say how long one is
+const STEPS: usize = 100;
+
+// events per bucket the bucket width is picked for when nobody asks for another number
+const DEFAULT_OCCUPANCY: f64 = 16.0;
 
 #[derive(Debug, Clone, Copy)]
 pub struct KeyVal {
     pub key:OrderedFloat<f64>, //the time the pair collides at
     pub val:csvreader::Rec, //all information p1,p2,p1x,p2x .. etc
-    pub id:(u32,u32),        //p1,p2
-    pub index: usize        //so it can be looked up easy within the data matrix
+    pub id:particle::Pair,   //p1,p2 smaller first, so both ways round are the same pair
+    pub index: usize,       //so it can be looked up easy within the data matrix
+    pub dt: f64             //the step get_next_time picks for the pair at this event, infinite until it is worked out
 }
-// KeyVal needs to be ordered so I can stick it in a priority queue
+// KeyVal needs to be ordered so I can stick it in a priority queue. Events at the same time go by
+// pair and then by where they sit in their step, so every queue agrees on one order no matter
+// which events happened to be pushed first or on which thread.
 impl Ord for KeyVal {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.key.cmp(&other.key)
+        (self.key, self.id, self.index).cmp(&(other.key, other.id, other.index))
     }
 }
 
@@ -42,7 +68,7 @@ impl PartialOrd for KeyVal {
 
 impl PartialEq for KeyVal {
     fn eq(&self, other: &Self) -> bool {
-        self.key == other.key
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -54,119 +80,119 @@ impl sequentialbucketqueue::HasKey for KeyVal {
     }
 }
 
-pub trait SeqentialPriorityQueue<'a, E: Ord> {
-    fn push(&mut self, e: &'a E);
-    fn pop(&mut self) -> Option<&E>;
-    fn is_empty(&self) -> bool;
-}
-
-impl <'a, E: Ord> SeqentialPriorityQueue<'a, E> for BinaryHeap<&'a E> {
-    fn push(&mut self, e: &'a E) {
-        BinaryHeap::push(self, e);
-    }
-    fn pop(&mut self) -> Option<&E> {
-        BinaryHeap::pop(self)
-    }
-    fn is_empty(&self) -> bool {
-        BinaryHeap::is_empty(self)
+impl uniquequeue::HasId for KeyVal {
+    type Id = particle::Pair;
+    fn id(&self) -> particle::Pair {
+        self.id
     }
 }
 
-pub trait ParallelPriorityQueue<'a, E: Ord + 'a> {
-    fn push(&mut self, e: &'a E);
-    fn pop(&mut self) -> Option<&E>;
-    fn is_empty(&self) -> bool;
-    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F);
-    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I);
-    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E>;
+impl blocktimestep::HasTimestep for KeyVal {
+    fn dt(&self) -> f64 {
+        self.dt
+    }
 }
 
-struct LockingBinaryHeap<'a, E: Ord + Send + Sync> {
-    bucketwidth: f64,
-    locked_heap: Mutex<BinaryHeap<&'a E>>
+/// What a queue promises about the element pop hands out, next to the smallest key it holds.
+/// The conformance tests hold every queue to what it declares here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PopOrder {
+    /// Always a smallest key.
+    Exact,
+    /// Keys are cut into `width` wide buckets and bulk_pop hands out the whole first non-empty one.
+    /// When `sorted` pop gives the smallest element of that bucket and bulk_pop hands it out smallest
+    /// first, otherwise any order goes.
+    Bucketed { width: f64, sorted: bool },
+    /// Some key less than this far past the smallest, for pop and for everything bulk_pop hands out.
+    Within(f64),
 }
 
-impl <'a, E: Ord + Send + Sync + HasKey> ParallelPriorityQueue<'a, E> for LockingBinaryHeap<'a, E> {
-    fn push(&mut self, e: &'a E) {
-        let mut bh = self.locked_heap.lock().unwrap();
-        bh.push(e);
-    }
-    fn pop(&mut self) -> Option<&E> {
-        let mut bh = self.locked_heap.lock().unwrap();
-        bh.pop()
-    }
-    fn is_empty(&self) -> bool {
-        let bh = self.locked_heap.lock().unwrap();
-        bh.is_empty()
-    }
-    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
-        let bucket = self.bulk_pop();
-        let mapped: Vec<&'a E> = bucket.map(f).flatten().collect();
-        self.bulk_push(mapped.into_par_iter());
-    }
-
-    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
-        es.for_each(|e| {
-            let mut bh = self.locked_heap.lock().unwrap();
-            bh.push(e);
-        });
-    }
-
-    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
-        let mut ret: Vec<&'a E> = Vec::new();
-        // TODO: implement skips
-        let mut bh = self.locked_heap.lock().unwrap();
-        let first_index = (bh.peek().unwrap().key()/self.bucketwidth).floor() as usize;
-        while !bh.is_empty() && (bh.peek().unwrap().key()/self.bucketwidth).floor() as usize == first_index {
-            ret.push(bh.pop().unwrap());
+/// What every queue in the crate can do one element at a time. Keys are event times and smaller
+/// keys come out first; queues that bucket their keys only promise that per bucket.
+pub trait SeqentialPriorityQueue<'a, E: Ord + HasKey + 'a> {
+    fn push(&mut self, e: &'a E);
+    fn pop(&mut self) -> Option<&'a E>;
+    /// The next element due. Queues that bucket their keys may pop a different element of the same bucket first.
+    fn peek(&self) -> Option<&'a E>;
+    fn is_empty(&self) -> bool;
+    fn len(&self) -> usize;
+    /// Removes everything but keeps whatever memory the queue already has.
+    fn clear(&mut self);
+    /// Removes and returns every element with a key before `time`. The default walks peek and pop,
+    /// which is only right for queues that pop in exact order, so bucketed queues override it.
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        let mut ret = Vec::new();
+        while self.peek().is_some_and(|e| e.key().0 < time) {
+            ret.push(self.pop().unwrap());
         }
-        ret.into_par_iter()
+        ret
     }
+    /// Makes room for at least `additional` more elements where the queue stores them contiguously.
+    /// Bucketed queues size their buckets up front and ignore this.
+    fn reserve(&mut self, _additional: usize) {}
+    fn pop_order(&self) -> PopOrder;
 }
 
-struct ParMutexBucket<'a, E: PartialOrd+Copy+Send+Sync> {
-    pub parabucket: parallelbucketqueue::ParBqueue<&'a E>
+/// Parallel queues also hand out and take back whole buckets at a time.
+pub trait ParallelPriorityQueue<'a, E: Ord + HasKey + Send + Sync + 'a>: SeqentialPriorityQueue<'a, E> {
+    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F);
+    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I);
+    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E>;
+    /// bulk_pop into a buffer the caller keeps handing back, cleared first. Queues that keep their
+    /// buckets in Vecs override this to trade allocations with the caller instead of copying.
+    fn bulk_pop_into(&mut self, buf: &mut Vec<&'a E>) {
+        buf.clear();
+        buf.par_extend(self.bulk_pop());
+    }
 }
 
-impl <'a, E: Copy + Ord + sequentialbucketqueue::HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for ParMutexBucket<'a, E> {
+// std's heap is a max-heap, so elements go in reversed to come out earliest first
+impl <'a, E: Ord + HasKey> SeqentialPriorityQueue<'a, E> for BinaryHeap<Reverse<&'a E>> {
     fn push(&mut self, e: &'a E) {
-        self.parabucket.push(e)
+        BinaryHeap::push(self, Reverse(e));
+    }
+    fn pop(&mut self) -> Option<&'a E> {
+        BinaryHeap::pop(self).map(|r| r.0)
     }
-    fn pop(&mut self) -> Option<&E> {
-        self.parabucket.pop()
+    fn peek(&self) -> Option<&'a E> {
+        BinaryHeap::peek(self).map(|r| r.0)
     }
     fn is_empty(&self) -> bool {
-        self.parabucket.is_empty()
+        BinaryHeap::is_empty(self)
     }
-    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
-        let bucket = self.bulk_pop();
-        let mapped: Vec<&'a E> = bucket.map(f).flatten().collect();
-        self.bulk_push(mapped.into_par_iter());
+    fn len(&self) -> usize {
+        BinaryHeap::len(self)
     }
-
-    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
-        self.parabucket.bulk_push(es);
+    fn clear(&mut self) {
+        BinaryHeap::clear(self)
     }
-
-    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
-        self.parabucket.bulk_pop()
+    fn reserve(&mut self, additional: usize) {
+        BinaryHeap::reserve(self, additional)
+    }
+    fn pop_order(&self) -> PopOrder {
+        PopOrder::Exact
     }
 }
 
-fn time_seqential<'a, PQ: SeqentialPriorityQueue<'a, KeyVal>>(data : &'a mut Vec<Vec<KeyVal>>, heap: &'a mut PQ) -> (Duration, i64, f64) {
+/// The std heap behind one lock, popping DELTA wide buckets like the bucket queues do.
+#[allow(dead_code)]
+type LockingBinaryHeap<'a, E> = adapters::Locked<BinaryHeap<Reverse<&'a E>>>;
+
+// Both drivers start a step from the first event of every pair in file order (partition::heads) and
+// push a pair's next event once the one before it is done, so every event in the dump gets processed
+// even where a pair's events are out of time order. Hand them a uniquequeue::UniqueQueue with
+// DuplicatePolicy::KeepEarliest all the same, so a queue never holds two events of one pair.
+// They return how long the run took and a digest of what it processed, so runs can be checked against each other.
+fn time_seqential<'a, PQ: SeqentialPriorityQueue<'a, KeyVal>>(data : &'a partition::Partition, heap: &'a mut PQ) -> (Duration, Digest) {
     let now = Instant::now();
-    let mut count = 0;
-    let mut time_sum = 0.0;
+    let mut digest = Digest::new();
 
-    for i in 0..data.len() {
+    for (i, step) in data.steps().enumerate() {
+        let tally = StepTally::new();
         // Add initial population of events. In a real simulation, this also happens in parallel because we are walking throug the tree in
         // parallel doing the search. I'm not certain how to model that here.
-        let mut ids = HashSet::new();
-        for k in &data[i] {
-            if !ids.contains(&k.id) {
-                heap.push(k);
-                ids.insert(k.id);
-            }
+        for &h in data.heads(i) {
+            heap.push(&step[h]);
         }
         // Process events in that step
         while !heap.is_empty() {
@@ -177,8 +203,7 @@ fn time_seqential<'a, PQ: SeqentialPriorityQueue<'a, KeyVal>>(data : &'a mut Vec
             let mut p1 = elem.val.p1();
             let mut p2 = elem.val.p2();
             let next_time = process_collision(&mut p1, &mut p2, elem.val.time);
-            count += 1;
-            time_sum += next_time;
+            tally.add(digest::event_hash(id, elem.val.time, next_time));
             // if next_time < (i + 1) as f64 * DELTA {
             //     let new_index = data[i].len();
             //     let next_event = KeyVal {
@@ -191,91 +216,288 @@ fn time_seqential<'a, PQ: SeqentialPriorityQueue<'a, KeyVal>>(data : &'a mut Vec
             //     heap.push(data[i].last().unwrap());
             // }
             //if the set contains another element with the same id push the first occuring element into the priority queue
-            data[i][index+1..].into_iter().find(|k| k.id == id).iter().for_each(|k| heap.push(k));
+            step[index+1..].iter().find(|k| k.id == id).iter().for_each(|k| heap.push(k));
         }
+        digest.push_step(tally);
     }
-    (now.elapsed(), count, time_sum)
+    (now.elapsed(), digest)
 }
 
-fn time_parallel<'a, PQ: ParallelPriorityQueue<'a, KeyVal>>(data : &'a Vec<Vec<KeyVal>>, heap: &'a mut PQ) -> (Duration, i64) {
+fn time_parallel<'a, PQ: ParallelPriorityQueue<'a, KeyVal>>(data : &'a partition::Partition, heap: &mut PQ) -> (Duration, Digest) {
     let now = Instant::now();
+    let mut digest = Digest::new();
 
-    for i in 0..data.len() {
-        // Add initial population of events.
-        let mut ids = HashSet::new();
-        let mut initial: Vec<&'a KeyVal> = Vec::new();
-        for k in &data[i] {
-            if !ids.contains(&k.id) {
-                initial.push(&k);
-                ids.insert(k.id);
-            }
-        }
-        heap.bulk_push(initial.into_par_iter());
-        // Process events in that step
+    for (i, step) in data.steps().enumerate() {
+        digest.push_step(run_step(step, data.heads(i), heap));
+    }
+    (now.elapsed(), digest)
+}
+
+// One global step of time_parallel, the queue comes back empty.
+fn run_step<'a, PQ: ParallelPriorityQueue<'a, KeyVal>>(step: &'a [KeyVal], heads: &[usize], heap: &mut PQ) -> StepTally {
+    let tally = StepTally::new();
+    // Add initial population of events, the first one of every pair
+    heap.bulk_push(heads.par_iter().map(|&h| &step[h]));
+    // Process events in that step
+    while !heap.is_empty() {
+        heap.bulk_process(|elem| {
+            let id = elem.id;
+            let index = elem.index;
+            let mut p1 = elem.val.p1();
+            let mut p2 = elem.val.p2();
+            let next_time = process_collision(&mut p1, &mut p2, elem.val.time);
+            tally.add(digest::event_hash(id, elem.val.time, next_time));
+            //if the set contains another element with the same id push the first occuring element into the priority queue
+            step[index+1..].iter().find(|k| k.id == id)
+        });
+    }
+    tally
+}
+
+// time_parallel for dumps that are not loaded up front: every step gets read in only once the
+// run gets to it and is dropped again when it is done. The queue borrows the step's events, so
+// each step gets a queue of its own, a timing wheel since it needs no buckets out to the step's
+// end time like a ParBqueue would. Only time spent in the queues counts, not reading the dump or
+// setting up the wheel. A step still goes in all at once when the run gets to it, events are not
+// fed in one by one as simulated time gets close to them.
+fn time_streaming(steps: impl Iterator<Item = Result<Vec<KeyVal>, Box<dyn std::error::Error>>>, width: f64) -> Result<(Duration, Digest), Box<dyn std::error::Error>> {
+    let mut elapsed = Duration::ZERO;
+    let mut digest = Digest::new();
+
+    for step in steps {
+        let step = step?;
+        let heads = partition::heads(&step);
+        let mut heap = uniquequeue::UniqueQueue::new(timingwheel::TimingWheel::new(width, 6, 11), uniquequeue::DuplicatePolicy::KeepEarliest);
+        let now = Instant::now();
+        digest.push_step(run_step(&step, &heads, &mut heap));
+        elapsed += now.elapsed();
+    }
+    Ok((elapsed, digest))
+}
+
+// Same run as time_parallel, but over the arena: the queue only sees KeyHandles and the pair's next
+// event is already known, so there is no scanning the step and no UniqueQueue.
+fn time_parallel_arena<'a, PQ: ParallelPriorityQueue<'a, arena::KeyHandle>>(events: &'a arena::EventArena, heap: &mut PQ) -> (Duration, Digest) {
+    let now = Instant::now();
+    let mut digest = Digest::new();
+
+    for i in 0..events.steps() {
+        let tally = StepTally::new();
+        heap.bulk_push(events.heads(i).par_iter().map(|&h| events.key(h)));
         while !heap.is_empty() {
-            heap.bulk_process(|elem| {
-                let id = elem.id;
-                let index = elem.index;
-                let mut p1 = elem.val.p1();
-                let mut p2 = elem.val.p2();
-                let next_time = process_collision(&mut p1, &mut p2, elem.val.time);
-                //if the set contains another element with the same id push the first occuring element into the priority queue
-                data[i][index+1..].into_iter().find(|k| k.id == id)
+            heap.bulk_process(|k| {
+                let mut p1 = events.p1(k.handle);
+                let mut p2 = events.p2(k.handle);
+                let next_time = process_collision(&mut p1, &mut p2, events.time(k.handle));
+                tally.add(digest::event_hash(events.pair(k.handle), events.time(k.handle), next_time));
+                events.next_key(k.handle)
             });
         }
+        digest.push_step(tally);
+    }
+    (now.elapsed(), digest)
+}
+
+// Prints a run's digest and, past the first run, whether it matches the first one.
+fn report_digest(name: &str, elapsed: Duration, digest: &Digest, first: &Option<Digest>) {
+    println!("{} Elapsed: {:.2?}, {}", name, elapsed, digest);
+    if let Some(first) = first {
+        match first.first_difference(digest) {
+            None => println!("  same as the first run"),
+            Some(step) => println!("  differs from the first run at step {}: {:?} then {:?}", step, first.steps.get(step), digest.steps.get(step)),
+        }
     }
-    (now.elapsed(), 0)
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let mut arecord = csvreader::csvcon(&args[1]).unwrap();
+    let mut args: Vec<String> = std::env::args().collect();
+
+    // `--density=<rho>` anywhere on the line is the density of this dataset's particles, for
+    // events that come without masses, over whatever the dump's header says
+    let density: Option<f64> = args.iter().find_map(|a| a.strip_prefix("--density=")).map(|d| d.parse().expect("--density needs a number"));
+    args.retain(|a| !a.starts_with("--density="));
+
+    // `validate [issues.csv]` checks the dump row by row instead of running it. The summary gets
+    // printed, the issues go to the file as csv if one is given and after the summary if not.
+    if args.get(2).is_some_and(|a| a == "validate") {
+        let report = validate::validate(&args[1]).unwrap();
+        println!("{}", report);
+        match args.get(3) {
+            Some(out) => report.write_csv(std::io::BufWriter::new(std::fs::File::create(out).unwrap())).unwrap(),
+            None if !report.is_clean() => report.write_csv(std::io::stdout().lock()).unwrap(),
+            None => {}
+        }
+        std::process::exit(if report.is_clean() { 0 } else { 1 });
+    }
+    // the run the dump came from, as far as its header says. Whatever it leaves out gets worked
+    // out from the events further down.
+    let meta = metadata::open(&args[1]).unwrap();
+    let density = density.or(meta.density).unwrap_or(particle::DEFAULT_DENSITY);
+
+    // `stream [step] [width]` replays the dump a global step at a time without ever loading all of
+    // it, for dumps too big for memory. There is no end time to divide up, so the step length has
+    // to be given when the header does not say.
+    if args.get(2).is_some_and(|a| a == "stream") {
+        // a number that does not parse is a mistake to point out, not a reason to quietly use the header's
+        let number = |i: usize, what: &str| args.get(i).map(|a| a.parse::<f64>().unwrap_or_else(|e| panic!("the {} has to be a number, not `{}`: {}", what, a, e)));
+        let step = number(3, "global step").or(meta.step).expect("stream needs the length of a global step, the dump's header has none");
+        let width = number(4, "bucket width").or(meta.bucket_width(DEFAULT_OCCUPANCY)).unwrap_or(DELTA);
+        println!("Bucket width: {:e}", width);
+        let steps = stream::Steps::new(stream::open(&args[1]).unwrap(), step).unwrap_or_else(|e| panic!("{}", e)).map(|s| s.map(|mut s| {
+            s.iter_mut().for_each(|k| {
+                k.val.resolve_masses(density);
+                k.dt = collisionhandling::timestep(k.val.p1(), k.val.p2(), k.val.time);
+            });
+            s
+        }));
+        let (elapsed, digest) = time_streaming(steps, width).unwrap_or_else(|e| panic!("{}", e));
+        println!("Streaming Timing Wheel Elapsed: {:.2?}, {}", elapsed, digest);
+        return;
+    }
+    // binary dumps are recognised by their magic, anything else not ending in .csv is taken to be a
+    // raw `Event:` dump straight from the simulation
+    let dump = bindump::is_dump(&args[1]).then(|| bindump::DumpFile::open(&args[1]).unwrap());
+    let loaded: Vec<csvreader::Rec>;
+    let arecord: &[csvreader::Rec] = if let Some(dump) = &dump {
+        dump.records()
+    } else {
+        loaded = if stream::is_csv(&args[1]) {
+            csvreader::par_csvcon(&args[1]).unwrap()
+        } else {
+            rawreader::rawcon(&args[1]).unwrap().into()
+        };
+        &loaded
+    };
+    // a binary dump written with a step table already says where every global step starts
+    let step_starts: Option<Vec<usize>> = dump.as_ref().and_then(|d| {
+        let steps = d.steps()?;
+        Some((0..steps).scan(0, |start, i| {
+            let at = *start;
+            *start += d.step(i).unwrap().len();
+            Some(at)
+        }).collect())
+    });
 
-    let mut data : Vec<Vec<KeyVal>> = vec![Vec::new();500];
-    
-    let mut max:f64= 0.0;
     //was orginally just a key value pair but, for the exculsion list to work
     // an id to idenitfy what index pair of rocks is in the this key value was added 
 
-
     //finds the end time of the entire data set
-    for i in 0..arecord.len() {
-        let poppy = arecord[i];
-        if poppy.time > max {
-            max = poppy.time;
+    let max:f64 = arecord.par_iter().map(|r| r.time).reduce(|| 0.0, f64::max);
+
+    //uses the max to find which global timestep each record belongs to then places them
+    //in the corresponding step, in the same order as they come in arecord
+    let mut data = match &step_starts {
+        Some(starts) => partition::partition(arecord, starts.len(), |n, _| Some(starts.partition_point(|&s| s <= n) - 1)),
+        None => {
+            // the header's global step, or a hundredth of the dump like it always was
+            let partitioner = match meta.step {
+                Some(step) => partition::Partitioner::with_length(step, max),
+                None => partition::Partitioner::with_count(STEPS, max),
+            }.unwrap_or_else(|e| panic!("{}", e));
+            println!("Global steps: {} of {:e}{}", partitioner.count(), partitioner.length(), if meta.step.is_some() { ", from the header" } else { "" });
+            partitioner.partition(arecord)
         }
-    }
-
-    while !arecord.is_empty() {
-        //here is why arecord is a dequeue as the entries into data need to be in the same order 
-        //as the entries into arecord
-        let poppy = arecord.pop_front().unwrap();
-        //uses the max to find which global timestep each record belongs to then places them 
-        //in the corresponding vector
-        let index:usize = (poppy.time/(max/100.0)).floor() as usize;
-        let ind = data[index].len();
-        data[index].push(KeyVal{key:OrderedFloat(poppy.time),val:poppy,id:(poppy.p1 as u32,poppy.p2 as u32),index: ind});
-
-    }
-
-    // let mut heapt = BinaryHeap::new();
+    }.unwrap();
+    //the step the simulation would pick for each event is what the block time-step bins go by, it
+    //needs the masses so it comes after them
+    data.par_events_mut().for_each(|k| {
+        k.val.resolve_masses(density);
+        k.dt = collisionhandling::timestep(k.val.p1(), k.val.p2(), k.val.time);
+    });
+
+    // let mut heapt = uniquequeue::UniqueQueue::new(BinaryHeap::new(), uniquequeue::DuplicatePolicy::KeepEarliest);
     // let elapsed = time_seqential(&mut data, &mut heapt);
     // println!("Binary Heap Elapsed: {:.2?}", elapsed);
 
-    // let mut heap1 = sequentialbucketqueue::Bqueue::new(((max/DELTA).ceil()+1.0) as usize,DELTA); //intialize the Bucket queue
+    // let mut heap1 = uniquequeue::UniqueQueue::new(sequentialbucketqueue::Bqueue::new(((max/DELTA).ceil()+1.0) as usize,DELTA), uniquequeue::DuplicatePolicy::KeepEarliest); //intialize the Bucket queue
     // let elapsed1 = time_seqential(&mut data, &mut heap1);
     // println!("Bucket Queue Elapsed: {:.2?}", elapsed1);
 
+    // let mut heap2 = uniquequeue::UniqueQueue::new(adapters::Sequential(parallelbucketqueue::ParBqueue::new(((max/DELTA).ceil()+1.0) as usize,DELTA)), uniquequeue::DuplicatePolicy::KeepEarliest);
+    // let elapsed2 = time_seqential(&mut data, &mut heap2);
+    // println!("Parallel Bucket Queue, one at a time, Elapsed: {:.2?}", elapsed2);
+
     //println!("{}",data[100].len());
     //println!("first p1: {}",arecord[0].p1);
 
     // Parallel
-    // let mut heap_bin_par = LockingBinaryHeap { bucketwidth: DELTA, locked_heap: Mutex::new(BinaryHeap::new()) };
+    // let heap_bin: LockingBinaryHeap<KeyVal> = adapters::Locked::new(BinaryHeap::new(), DELTA);
+    // let mut heap_bin_par = uniquequeue::UniqueQueue::new(heap_bin, uniquequeue::DuplicatePolicy::KeepEarliest);
     // let elapsed = time_parallel(&data, &mut heap_bin_par);
     // println!("Binary Heap Elapsed: {:.2?}", elapsed);
 
-    let mut heap_bucket_par: parallelbucketqueue::ParBqueue<&KeyVal> = parallelbucketqueue::ParBqueue::new(((max/DELTA).ceil()+1.0) as usize,DELTA); //intialize the Bucket queue
-    let elapsed1 = time_parallel(&data, &mut heap_bucket_par);
-    println!("Bucket Queue Elapsed: {:.2?}", elapsed1);
+    // `convert <out>` writes what was just loaded out step by step and stops there: as csv for a
+    // .csv file, as `Event:` lines for a .txt or .dump file and as a binary dump, steps and all, otherwise
+    if args.get(2).is_some_and(|a| a == "convert") {
+        let out = args.get(3).expect("convert needs a file to write to");
+        let mut w = std::io::BufWriter::new(std::fs::File::create(out).unwrap());
+        let steps: Vec<_> = data.steps().map(|step| step.iter().map(|k| &k.val)).collect();
+        // the header goes along, with the density the masses were just worked out with
+        let meta = metadata::Metadata { density: Some(density), ..meta.clone() };
+        if stream::is_csv(out) {
+            meta.write(&mut w).unwrap();
+            csvreader::write_csv(&mut w, steps.iter().cloned().flatten()).unwrap();
+        } else if out.ends_with(".txt") || out.ends_with(".dump") {
+            meta.write(&mut w).unwrap();
+            rawreader::write_events(&mut w, steps.iter().cloned().flatten()).unwrap();
+        } else {
+            bindump::write_steps(&mut w, &steps, &meta).unwrap();
+        }
+        std::io::Write::flush(&mut w).unwrap();
+        println!("Wrote {} events in {} steps to {}", data.event_count(), steps.len(), out);
+        return;
+    }
+
+    // `verify` as the second argument replays the dump through every queue and compares what they
+    // processed instead of timing anything, the other arguments then move one along
+    let verify = args.get(2).is_some_and(|a| a == "verify");
+    let opts = &args[if verify { 3 } else { 2 }..];
+
+    // an optional second argument asks for the bucket width to be picked from the events so that
+    // each bucket holds that many events on average. Without one the header's run decides, and
+    // for dumps without a header the events do after all.
+    let from_gaps = |occupancy: f64| {
+        let times: Vec<f64> = data.events().map(|k| k.val.time).collect();
+        tuning::width_from_gaps(&times, occupancy)
+    };
+    let width = match opts.first().map(|a| a.parse::<f64>()) {
+        Some(Ok(occupancy)) => from_gaps(occupancy),
+        _ => meta.bucket_width(DEFAULT_OCCUPANCY).or_else(|| from_gaps(DEFAULT_OCCUPANCY)),
+    }.unwrap_or(DELTA);
+    println!("Bucket width: {:e}", width);
+
+    if verify {
+        let agreed = equivalence::report(&data, ((max/width).ceil()+1.0) as usize, width);
+        std::process::exit(if agreed { 0 } else { 1 });
+    }
+
+    let heap_bucket: parallelbucketqueue::ParBqueue<&KeyVal> = parallelbucketqueue::ParBqueue::new(((max/width).ceil()+1.0) as usize,width); //intialize the Bucket queue
+    let mut heap_bucket_par = uniquequeue::UniqueQueue::new(heap_bucket, uniquequeue::DuplicatePolicy::KeepEarliest);
+    // an optional third argument repeats the run on the same queue, clearing it keeps the buckets
+    // it grew so only the first run pays for allocating them
+    let runs = opts.get(1).and_then(|a| a.parse::<usize>().ok()).unwrap_or(1);
+    // every run is held to the digest of the first, the arena runs included
+    let mut first: Option<Digest> = None;
+    for _ in 0..runs {
+        heap_bucket_par.clear();
+        let (elapsed1, digest1) = time_parallel(&data, &mut heap_bucket_par);
+        report_digest("Bucket Queue", elapsed1, &digest1, &first);
+        first.get_or_insert(digest1);
+    }
+
+    let events = arena::EventArena::from_steps(data.steps().map(|step| step.iter().map(|k| &k.val)));
+    let mut heap_arena: parallelbucketqueue::ParBqueue<&arena::KeyHandle> = parallelbucketqueue::ParBqueue::new(((max/width).ceil()+1.0) as usize,width);
+    for _ in 0..runs {
+        heap_arena.clear();
+        let (elapsed3, digest3) = time_parallel_arena(&events, &mut heap_arena);
+        report_digest("Arena Bucket Queue", elapsed3, &digest3, &first);
+    }
+
+    let mut heap_block = uniquequeue::UniqueQueue::new(blocktimestep::BlockTimestep::new(width*64.0, 16), uniquequeue::DuplicatePolicy::KeepEarliest); //finest bin is width/512
+    for _ in 0..runs {
+        heap_block.clear();
+        let (elapsed2, digest2) = time_parallel(&data, &mut heap_block);
+        report_digest("Block Timestep", elapsed2, &digest2, &first);
+    }
 
 }
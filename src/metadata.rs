@@ -0,0 +1,166 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+use crate::bindump::{self, DumpFile};
+
+/// What the simulation says about the run a dump came from, as `# key=value` lines at the top of a
+/// csv or raw dump. Everything is optional, keys it does not know are kept as they were written.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub step: Option<f64>,        // length of a global step
+    pub particles: Option<usize>, // how many particles the run had
+    pub units: Option<String>,
+    pub restitution: Option<f64>,
+    pub density: Option<f64>,     // of the particles, for events that come without masses
+    pub other: Vec<(String, String)>,
+}
+
+// starts every metadata line, `#` lines without a `=` are plain comments
+const PREFIX: char = '#';
+
+// "Global Step", "global_step" and "step" all name the step
+fn normalise(key: &str) -> String {
+    key.chars().filter(|c| !matches!(c, '.' | '_' | '-') && !c.is_whitespace()).collect::<String>().to_lowercase()
+}
+
+fn positive(key: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(x) if x > 0.0 && x.is_finite() => Ok(x),
+        Ok(_) => Err(format!("`{}` has to be a positive number, not {}", key, value)),
+        Err(e) => Err(format!("`{}` is `{}`: {}", key, value, e)),
+    }
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Takes one `key=value`, checking that the value makes sense for the keys it knows.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let (key, value) = (key.trim(), value.trim());
+        match normalise(key).as_str() {
+            "step" | "globalstep" => self.step = Some(positive(key, value)?),
+            "particles" | "particlecount" | "n" => {
+                self.particles = Some(value.parse().map_err(|e| format!("`{}` is `{}`: {}", key, value, e))?)
+            }
+            "units" => self.units = Some(value.to_string()),
+            "restitution" | "cor" => match value.parse::<f64>() {
+                Ok(e) if (0.0..=1.0).contains(&e) => self.restitution = Some(e),
+                _ => return Err(format!("`{}` has to be between 0 and 1, not {}", key, value)),
+            },
+            "density" | "rho" => self.density = Some(positive(key, value)?),
+            _ => self.other.push((key.to_string(), value.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Reads the `#` lines at the top of a dump, up to the first line that is not one.
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut meta = Self::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let Some(entry) = line.trim_start().strip_prefix(PREFIX) else { break };
+            if let Some((key, value)) = entry.split_once('=') {
+                meta.set(key, value).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            }
+        }
+        Ok(meta)
+    }
+
+    /// Every key with its value, the known ones first.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let mut entries: Vec<(String, String)> = [
+            ("step", self.step.map(|x| format!("{:?}", x))),
+            ("particles", self.particles.map(|n| n.to_string())),
+            ("units", self.units.clone()),
+            ("restitution", self.restitution.map(|x| format!("{:?}", x))),
+            ("density", self.density.map(|x| format!("{:?}", x))),
+        ].into_iter().filter_map(|(key, value)| Some((key.to_string(), value?))).collect();
+        entries.extend(self.other.iter().cloned());
+        entries
+    }
+
+    /// Writes the `# key=value` lines read reads back, nothing at all when there is no metadata.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (key, value) in self.entries() {
+            writeln!(w, "{} {}={}", PREFIX, key, value)?;
+        }
+        Ok(())
+    }
+
+    /// A bucket width that puts about `occupancy` events in a bucket. A run sees roughly one event
+    /// per particle per global step, so that is `occupancy` particles' worth of a step.
+    pub fn bucket_width(&self, occupancy: f64) -> Option<f64> {
+        Some(self.step? * occupancy / self.particles.filter(|&n| n > 0)? as f64)
+    }
+}
+
+/// The metadata of the dump at `path`: binary dumps keep it in their own block, csv and raw dumps
+/// in the `#` lines they start with.
+pub fn open(path: &str) -> Result<Metadata, Box<dyn Error>> {
+    let path = path.replace("\"","").replace("\\","/");
+    let path = path.trim();
+    if bindump::is_dump(path) {
+        Ok(DumpFile::open(path)?.metadata().clone())
+    } else {
+        Metadata::read(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn reads_the_header_lines() {
+        let dump = "\
+# Global Step = 0.01
+# particles=400
+#units=au, years/2pi
+# restitution=0.5
+# written by the run on the cluster
+# seed=42
+p1,p2,time
+# density=3
+";
+        let meta = Metadata::read(dump.as_bytes()).unwrap();
+        assert_eq!(meta.step, Some(0.01));
+        assert_eq!(meta.particles, Some(400));
+        assert_eq!(meta.units.as_deref(), Some("au, years/2pi"));
+        assert_eq!(meta.restitution, Some(0.5));
+        assert_eq!(meta.density, None);
+        assert_eq!(meta.other, [("seed".to_string(), "42".to_string())]);
+        assert_eq!(meta.bucket_width(16.0), Some(0.01 * 16.0 / 400.0));
+
+        assert!(Metadata::read("p1,p2,time\n".as_bytes()).unwrap().is_empty());
+        assert_eq!(Metadata::new().bucket_width(16.0), None);
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let meta = Metadata::read("# density=2.5\n# seed=42\n# step=0.1\n# units=cgs\n".as_bytes()).unwrap();
+        let mut out = Vec::new();
+        meta.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), "# step=0.1\n# units=cgs\n# density=2.5\n# seed=42\n");
+        assert_eq!(Metadata::read(out.as_slice()).unwrap(), meta);
+
+        let mut out = Vec::new();
+        Metadata::new().write(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn refuses_values_that_make_no_sense() {
+        for (line, why) in [("# step=0", "positive"), ("# step=x", "`step` is `x`"), ("# rho=-1", "positive"), ("# cor=1.5", "between 0 and 1"), ("# particles=4.5", "`particles`")] {
+            let err = Metadata::read(format!("# units=si\n{}\n", line).as_bytes()).unwrap_err().to_string();
+            assert!(err.starts_with("line 2: ") && err.contains(why), "{}: {}", line, err);
+        }
+    }
+}
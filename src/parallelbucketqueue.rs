@@ -1,106 +1,232 @@
-use std::sync::Mutex;
 use rayon::prelude::*;
 
-use crate::{sequentialbucketqueue::HasKey, ParallelPriorityQueue};
+// loom swaps in its own Mutex and atomics so the tests below can walk every interleaving
+#[cfg(not(loom))]
+use std::sync::{atomic::{AtomicUsize, Ordering}, Mutex};
+#[cfg(loom)]
+use loom::sync::{atomic::{AtomicUsize, Ordering}, Mutex};
 
+use crate::{sequentialbucketqueue::HasKey, tuning::{self, Retuner}, ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
+
+/// Bucket queue that bulk_push can fill from many threads at once. Pushing only needs `&self`
+/// (see push_shared), every bucket has its own lock and `start` only ever moves down while pushes
+/// are going on, so it can not end up past a bucket somebody just filled.
 #[derive(Debug)]
 pub struct ParBqueue<T:Send>{
     bucketwidth: f64,
     data: Vec<Mutex<Vec<T>>>,
-    start: usize
+    start: AtomicUsize,
+    len: AtomicUsize,
+    retuner: Option<Retuner>,
+    spare: Vec<T> // bulk_process pops into this and hands it back, so buckets keep trading the same allocations
 }
 
-impl<'a, T:HasKey + Send + Sync> ParBqueue<&'a T> {
+impl<'a, T:HasKey + PartialOrd + Send + Sync> ParBqueue<&'a T> {
     pub fn new(bucketnum: usize, bucketwidth: f64) -> Self {
         let mut datas:Vec<Mutex<Vec<&'a T>>> = Vec::with_capacity(bucketnum);
         (0..bucketnum).into_iter().for_each(|_i| {
             datas.push(Mutex::new(Vec::new()));
         });
         return Self {
-            start: bucketnum,
+            start: AtomicUsize::new(bucketnum),
+            len: AtomicUsize::new(0),
             bucketwidth,
-            data: datas
+            data: datas,
+            retuner: None,
+            spare: Vec::new()
         }
     }
 
+    #[allow(dead_code)]
+    /// Same as new, but re-buckets in place whenever the retuner sees the popped buckets drift off target.
+    pub fn with_retuner(bucketnum: usize, bucketwidth: f64, retuner: Retuner) -> Self {
+        let mut q = Self::new(bucketnum, bucketwidth);
+        q.retuner = Some(retuner);
+        q
+    }
+
+    #[allow(dead_code)]
+    pub fn bucketwidth(&self) -> f64 {
+        self.bucketwidth
+    }
+
+    /// Moves every element into buckets of the new width, keeping the same key horizon. The width
+    /// moves at most `tuning::MAX_RETUNE` times either way, however far off the asked for one is.
+    pub fn rebucket(&mut self, bucketwidth: f64) {
+        let (bucketwidth, bucketnum) = tuning::retuned(self.data.len(), self.bucketwidth, bucketwidth);
+        let old = std::mem::replace(&mut self.data, (0..bucketnum).map(|_| Mutex::new(Vec::new())).collect());
+        self.bucketwidth = bucketwidth;
+        self.start.store(bucketnum, Ordering::Relaxed);
+        self.len.store(0, Ordering::Relaxed);
+        let elems: Vec<&'a T> = old.into_iter().flat_map(|b| b.into_inner().unwrap()).collect();
+        self.bulk_push(elems.into_par_iter());
+    }
+
     pub fn push(&mut self, elem: &'a T) {
+        self.push_shared(elem);
+    }
+
+    /// push for when other threads are pushing at the same time. The element goes into its bucket
+    /// before `start` is lowered to it, so whoever sees the new `start` also finds the element there.
+    pub fn push_shared(&self, elem: &'a T) {
         let index = (elem.key()/self.bucketwidth).floor() as usize;
         self.data[index].lock().unwrap().push(elem);
-        if index < self.start {
-            self.start = index;
-        }
+        self.len.fetch_add(1, Ordering::AcqRel);
+        self.start.fetch_min(index, Ordering::AcqRel);
     }
 
-    pub fn pop(&mut self) -> Option<&T>{
+    pub fn pop(&mut self) -> Option<&'a T>{
         if self.is_empty() {
             return None
         } else {
-            let y = self.data[self.start].lock().unwrap().pop();
-            while self.start < self.data.len() && self.data[self.start].lock().unwrap().is_empty() {
-                self.start = self.start +1;
-            }
+            // buckets fill up in whatever order the threads got there, so look for the smallest
+            let bucket = self.data[self.start.load(Ordering::Relaxed)].get_mut().unwrap();
+            let y = smallest(bucket).map(|at| bucket.swap_remove(at));
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            self.advance_start();
             return y
         }
     }
 
-    pub fn peek(&self) -> Option<&T> {
+    pub fn peek(&self) -> Option<&'a T> {
         if self.is_empty() {
             return None
-        } else {
-            return Some(self.data[self.start].lock().unwrap()[0]);
         }
+        let bucket = self.data[self.start.load(Ordering::Acquire)].lock().unwrap();
+        smallest(&bucket).map(|at| bucket[at])
     }
 
     pub fn is_empty(&self) -> bool {
-        return self.start >= self.data.len();
+        self.start.load(Ordering::Acquire) >= self.data.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Empties every bucket without giving back their memory.
+    pub fn clear(&mut self) {
+        for bucket in self.data.iter_mut() {
+            bucket.get_mut().unwrap().clear();
+        }
+        self.start.store(self.data.len(), Ordering::Relaxed);
+        self.len.store(0, Ordering::Relaxed);
+    }
+
+    /// Empties the queue for a new run over `bucketnum` buckets of `bucketwidth`, reusing the buckets
+    /// (and whatever they had allocated) from the last one.
+    #[allow(dead_code)]
+    pub fn reset(&mut self, bucketnum: usize, bucketwidth: f64) {
+        self.clear();
+        self.data.resize_with(bucketnum, || Mutex::new(Vec::new()));
+        self.bucketwidth = bucketwidth;
+        self.start.store(bucketnum, Ordering::Relaxed);
+    }
+
+    /// Removes every element with a key before `time`, whole buckets first and then whatever
+    /// part of the bucket `time` falls in.
+    pub fn drain_until(&mut self, time: f64) -> Vec<&'a T> {
+        let mut ret = Vec::new();
+        let end = ((time/self.bucketwidth).floor().max(0.0) as usize).min(self.data.len());
+        let start = self.start.load(Ordering::Relaxed);
+        for bucket in self.data[start.min(end)..end].iter_mut() {
+            ret.append(bucket.get_mut().unwrap());
+        }
+        if end < self.data.len() {
+            let bucket = self.data[end].get_mut().unwrap();
+            ret.extend(bucket.iter().filter(|e| e.key().0 < time));
+            bucket.retain(|e| e.key().0 >= time);
+        }
+        self.len.fetch_sub(ret.len(), Ordering::Relaxed);
+        self.advance_start();
+        ret
     }
 
     pub fn bulk_process<F: Fn(&'a T) -> Option<&'a T> + Sync + Send>(&mut self, f: F) {
-        let bucket = self.bulk_pop();
-        let mapped = bucket.map(f).flatten();
-        self.bulk_push(mapped);
+        let mut bucket = std::mem::take(&mut self.spare);
+        self.bulk_pop_into(&mut bucket);
+        let queue = &*self;
+        bucket.par_iter().filter_map(|e| f(e)).for_each(|e| queue.push_shared(e));
+        self.spare = bucket;
     }
 
     pub fn bulk_push<I: ParallelIterator<Item = &'a T>>(&mut self, es: I) {
         // TODO: This can be smarter, but it comes with overhead. Groupby the index and push all indices at once.
-        let indices = es.map(|i| {
-            let index = (i.key()/self.bucketwidth).floor() as usize;
-            self.data[index].lock().unwrap().push(i);
-            index
-        });
-        let mindex_opt = indices.min();
-        if let Some(mindex) = mindex_opt {
-            if self.start > mindex {
-                self.start = mindex;
-            }
-        }
+        let queue = &*self;
+        es.for_each(|i| queue.push_shared(i));
     }
 
     pub fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a T> {
-        self.data.push(Mutex::new(Vec::new()));
-        let bucket = self.data.swap_remove(self.start).into_inner().unwrap();
-        // println!("{}", bucket.len());
+        self.bulk_pop_vec().into_par_iter()
+    }
+
+    fn bulk_pop_vec(&mut self) -> Vec<&'a T> {
+        let mut bucket = Vec::new();
+        self.bulk_pop_into(&mut bucket);
+        bucket
+    }
+
+    /// Swaps the first bucket with `buf`, so the bucket's elements end up in `buf` and the bucket
+    /// keeps `buf`'s allocation. Handing the same Vec back every time means nothing gets allocated
+    /// once the buckets have grown to size. The bucket comes out in whatever order its elements were
+    /// pushed in, bulk_process handles it in parallel anyway so sorting it would only cost time.
+    pub fn bulk_pop_into(&mut self, buf: &mut Vec<&'a T>) {
+        buf.clear();
+        if self.is_empty() {
+            return;
+        }
+        let start = self.start.load(Ordering::Relaxed);
+        std::mem::swap(self.data[start].get_mut().unwrap(), buf);
+        self.len.fetch_sub(buf.len(), Ordering::Relaxed);
+        // println!("{}", buf.len());
         self.advance_start();
-        bucket.into_par_iter()
+        let suggestion = self.retuner.as_mut().and_then(|r| r.observe(buf.len(), self.bucketwidth));
+        if let Some(width) = suggestion {
+            self.rebucket(width);
+        }
     }
 
     fn advance_start(&mut self) {
-        while self.start < self.data.len() && self.data[self.start].lock().unwrap().is_empty() {
-            self.start = self.start +1;
+        let mut start = self.start.load(Ordering::Relaxed);
+        while start < self.data.len() && self.data[start].get_mut().unwrap().is_empty() {
+            start += 1;
         }
+        self.start.store(start, Ordering::Relaxed);
     }
 }
 
-impl <'a, E: Ord + HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for ParBqueue<&'a E> {
+fn smallest<T: PartialOrd>(bucket: &[T]) -> Option<usize> {
+    (0..bucket.len()).reduce(|a, b| if bucket[b] < bucket[a] { b } else { a })
+}
+
+impl <'a, E: Ord + HasKey + Send + Sync> SeqentialPriorityQueue<'a, E> for ParBqueue<&'a E> {
     fn push(&mut self, e: &'a E) {
         ParBqueue::push(self, e);
     }
-    fn pop(&mut self) -> Option<&E> {
+    fn pop(&mut self) -> Option<&'a E> {
         ParBqueue::pop(self)
     }
+    fn peek(&self) -> Option<&'a E> {
+        ParBqueue::peek(self)
+    }
     fn is_empty(&self) -> bool {
         ParBqueue::is_empty(self)
     }
+    fn len(&self) -> usize {
+        ParBqueue::len(self)
+    }
+    fn clear(&mut self) {
+        ParBqueue::clear(self)
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        ParBqueue::drain_until(self, time)
+    }
+    fn pop_order(&self) -> PopOrder {
+        PopOrder::Bucketed { width: self.bucketwidth, sorted: false }
+    }
+}
+
+impl <'a, E: Ord + HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for ParBqueue<&'a E> {
     fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
         ParBqueue::bulk_process(self, f);
     }
@@ -110,6 +236,9 @@ impl <'a, E: Ord + HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for ParBqu
     fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
         ParBqueue::bulk_pop(self)
     }
+    fn bulk_pop_into(&mut self, buf: &mut Vec<&'a E>) {
+        ParBqueue::bulk_pop_into(self, buf)
+    }
 }
 
 #[cfg(test)]
@@ -179,4 +308,182 @@ mod tests {
         assert_eq!(heap1.is_empty(), true);
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rebucket_keeps_elements() {
+        let values: Vec<f64> = (0..200).map(|i| i as f64 * 0.37).collect();
+        let mut heap1: ParBqueue<&f64> = ParBqueue::with_retuner(100, 1.0, Retuner::new(8.0, 1.5, 2));
+        heap1.bulk_push(values.par_iter());
+        let mut count = 0;
+        let mut last = f64::NEG_INFINITY;
+        while !heap1.is_empty() {
+            let bucket: Vec<&f64> = heap1.bulk_pop().collect();
+            let lo = bucket.iter().fold(f64::INFINITY, |a, &&b| a.min(b));
+            assert!(lo >= last);
+            last = bucket.iter().fold(f64::NEG_INFINITY, |a, &&b| a.max(b));
+            count += bucket.len();
+        }
+        assert!(heap1.bucketwidth() > 1.0);
+        assert_eq!(count, values.len());
+    }
+
+    #[test]
+    fn narrowing_retune_is_bounded() {
+        let values: Vec<f64> = (0..10000).map(|i| 0.5 + i as f64 * 1e-5).collect();
+        let mut heap1: ParBqueue<&f64> = ParBqueue::with_retuner(10, 1.0, Retuner::new(1.0, 1.5, 1));
+        heap1.bulk_push(values.par_iter());
+        let mut count = heap1.bulk_pop().count();
+        assert_eq!(heap1.bucketwidth(), 1.0 / tuning::MAX_RETUNE);
+        assert!(heap1.data.len() <= 10 * tuning::MAX_RETUNE as usize + 1);
+        while !heap1.is_empty() {
+            count += heap1.bulk_pop().count();
+        }
+        assert_eq!(count, values.len());
+    }
+
+    #[test]
+    fn drain_until_and_clear() {
+        let values: Vec<f64> = (0..100).map(|i| i as f64 * 0.3).collect();
+        let mut heap1: ParBqueue<&f64> = ParBqueue::new(40, 1.0);
+        heap1.bulk_push(values.par_iter());
+        assert_eq!(heap1.len(), 100);
+        let mut drained = heap1.drain_until(10.6);
+        drained.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(drained, values[..36].iter().collect::<Vec<_>>());
+        assert_eq!(heap1.len(), 64);
+        // only 10.8 is left of the bucket 10.6 fell in
+        assert_eq!(heap1.bulk_pop().count(), 1);
+        assert_eq!(heap1.len(), 63);
+
+        heap1.clear();
+        assert!(heap1.is_empty());
+        assert_eq!(heap1.len(), 0);
+        assert_eq!(heap1.bulk_pop().count(), 0);
+    }
+
+    #[test]
+    fn buffers_get_reused() {
+        let values: Vec<f64> = (0..100).map(|i| i as f64 * 0.1).collect();
+        let mut heap1: ParBqueue<&f64> = ParBqueue::new(12, 1.0);
+        heap1.bulk_push(values.par_iter());
+
+        let mut buf = Vec::with_capacity(64);
+        let ptr = buf.as_ptr();
+        heap1.bulk_pop_into(&mut buf);
+        assert_eq!(buf.len(), 10);
+        // the popped bucket now holds the buffer that was handed in
+        assert_eq!(heap1.data[0].get_mut().unwrap().as_ptr(), ptr);
+        assert_eq!(heap1.data[0].get_mut().unwrap().capacity(), 64);
+        assert_eq!(heap1.len(), 90);
+
+        heap1.bulk_process(|_| None);
+        assert_eq!(heap1.len(), 80);
+        assert!(heap1.spare.capacity() >= 10);
+
+        heap1.reset(30, 0.5);
+        assert!(heap1.is_empty());
+        assert_eq!(heap1.len(), 0);
+        assert_eq!(heap1.data.len(), 30);
+        assert!(heap1.data[5].get_mut().unwrap().capacity() >= 10);
+        heap1.bulk_push(values.par_iter());
+        heap1.bulk_pop_into(&mut buf);
+        assert_eq!(buf, values[..5].iter().collect::<Vec<_>>());
+    }
+}
+
+// Run with RUSTFLAGS="--cfg loom" cargo test --release loom_tests, the other tests can not run under
+// loom since its Mutex only works inside loom::model.
+#[cfg(loom)]
+mod loom_tests {
+
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use super::*;
+
+    static VALUES: [f64; 6] = [2.5, 0.5, 2.25, 1.75, 0.75, 3.5];
+
+    // everything before start is empty and start itself is not, unless the queue is
+    fn start_is_tight(q: &ParBqueue<&f64>) {
+        let start = q.start.load(Ordering::SeqCst);
+        for bucket in &q.data[..start.min(q.data.len())] {
+            assert!(bucket.lock().unwrap().is_empty(), "start skipped a non-empty bucket");
+        }
+        if start < q.data.len() {
+            assert!(!q.data[start].lock().unwrap().is_empty(), "start sits on an empty bucket");
+        }
+    }
+
+    fn pop_all(q: &mut ParBqueue<&'static f64>) -> Vec<f64> {
+        let mut popped = Vec::new();
+        let mut last = f64::NEG_INFINITY;
+        while !q.is_empty() {
+            let bucket = q.bulk_pop_vec();
+            assert!(!bucket.is_empty());
+            assert!(bucket.iter().all(|v| **v >= last));
+            last = bucket.iter().fold(last, |a, v| a.max(**v));
+            start_is_tight(q);
+            popped.extend(bucket.into_iter().copied());
+        }
+        assert_eq!(q.len(), 0);
+        popped.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        popped
+    }
+
+    fn sorted(values: &[f64]) -> Vec<f64> {
+        let mut values = values.to_vec();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values
+    }
+
+    #[test]
+    fn concurrent_pushes() {
+        loom::model(|| {
+            let q = Arc::new(ParBqueue::new(5, 1.0));
+            let pushers: Vec<_> = [&VALUES[..2], &VALUES[2..4]].into_iter().map(|values| {
+                let q = q.clone();
+                thread::spawn(move || values.iter().for_each(|v| q.push_shared(v)))
+            }).collect();
+            // whatever a reader sees half way through has to really be in there
+            if let Some(v) = q.peek() {
+                assert!(VALUES[..4].contains(v));
+            }
+            for pusher in pushers {
+                pusher.join().unwrap();
+            }
+
+            let mut q = Arc::try_unwrap(q).unwrap();
+            start_is_tight(&q);
+            assert_eq!(q.len(), 4);
+            assert_eq!(pop_all(&mut q), sorted(&VALUES[..4]));
+        });
+    }
+
+    #[test]
+    fn pushes_after_bulk_pop() {
+        loom::model(|| {
+            let mut q = ParBqueue::new(5, 1.0);
+            VALUES[2..].iter().for_each(|v| q.push(v));
+            // takes out 0.75, so start moves up to the bucket of 1.75
+            assert_eq!(q.bulk_pop_vec(), vec![&VALUES[4]]);
+            start_is_tight(&q);
+
+            // two threads push one element back into the bucket just popped and one further on, like bulk_process does
+            let q = Arc::new(q);
+            let pushers: Vec<_> = [&VALUES[0..1], &VALUES[1..2]].into_iter().map(|values| {
+                let q = q.clone();
+                thread::spawn(move || values.iter().for_each(|v| q.push_shared(v)))
+            }).collect();
+            for pusher in pushers {
+                pusher.join().unwrap();
+            }
+
+            let mut q = Arc::try_unwrap(q).unwrap();
+            start_is_tight(&q);
+            assert_eq!(q.len(), 5);
+            let mut expected = VALUES.to_vec();
+            expected.remove(4);
+            assert_eq!(pop_all(&mut q), sorted(&expected));
+        });
+    }
+}
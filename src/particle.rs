@@ -1,10 +1,15 @@
 use core::fmt;
-use std::{f64::consts::PI, hash::Hash};
+use std::{error::Error, f64::consts::PI, hash::Hash, str::FromStr};
 
 use crate::vectors::Vector;
 
+/// Density of particles whose dump gives no mass, unless the run is told otherwise.
+pub const DEFAULT_DENSITY: f64 = 1.0;
+
 /// A wrapper for increased type safety
-#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+// transparent and Pod so csvreader::Rec can hold them and still be read straight out of a binary dump
+#[derive(Clone, Debug, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(transparent)]
 pub struct ParticleIndex(pub usize);
 
 impl fmt::Display for ParticleIndex {
@@ -14,6 +19,120 @@ impl fmt::Display for ParticleIndex {
     }
 }
 
+// floats are exact up to here, past it two ids could read as the same number
+const MAX_EXACT: f64 = (1u64 << 53) as f64;
+
+/// Why a value is no particle index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdError {
+    NotANumber(String),
+    Negative(f64),
+    Fractional(f64),
+    TooLarge(f64),
+}
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdError::NotANumber(text) => write!(f, "particle id `{}` is not a number", text),
+            IdError::Negative(x) => write!(f, "particle id {} is negative", x),
+            IdError::Fractional(x) => write!(f, "particle id {} is not a whole number", x),
+            IdError::TooLarge(x) => write!(f, "particle id {} is too large", x),
+        }
+    }
+}
+
+impl Error for IdError {}
+
+impl TryFrom<f64> for ParticleIndex {
+    type Error = IdError;
+
+    /// Older dumps write ids as floats, `113.0` is fine, `113.5` or `-1` are not.
+    fn try_from(x: f64) -> Result<Self, IdError> {
+        if x.is_nan() {
+            Err(IdError::NotANumber(x.to_string()))
+        } else if x < 0.0 {
+            Err(IdError::Negative(x))
+        } else if x > MAX_EXACT || x > usize::MAX as f64 {
+            Err(IdError::TooLarge(x))
+        } else if x.fract() != 0.0 {
+            Err(IdError::Fractional(x))
+        } else {
+            Ok(ParticleIndex(x as usize))
+        }
+    }
+}
+
+impl FromStr for ParticleIndex {
+    type Err = IdError;
+
+    /// Integers are taken as they are, anything else has to be a float that is a whole number.
+    fn from_str(text: &str) -> Result<Self, IdError> {
+        let text = text.trim();
+        if let Ok(i) = text.parse::<u64>() {
+            return usize::try_from(i).map(ParticleIndex).map_err(|_| IdError::TooLarge(i as f64));
+        }
+        let x: f64 = text.parse().map_err(|_| IdError::NotANumber(text.to_string()))?;
+        ParticleIndex::try_from(x)
+    }
+}
+
+// written as a plain integer, read back from anything csv hands over, `113` and `113.0` alike
+impl serde::Serialize for ParticleIndex {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(self.0 as u64)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ParticleIndex {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = ParticleIndex;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a particle id")
+            }
+            fn visit_u64<E: serde::de::Error>(self, i: u64) -> Result<ParticleIndex, E> {
+                usize::try_from(i).map(ParticleIndex).map_err(|_| E::custom(IdError::TooLarge(i as f64)))
+            }
+            fn visit_i64<E: serde::de::Error>(self, i: i64) -> Result<ParticleIndex, E> {
+                u64::try_from(i).map_err(|_| E::custom(IdError::Negative(i as f64))).and_then(|i| self.visit_u64(i))
+            }
+            fn visit_f64<E: serde::de::Error>(self, x: f64) -> Result<ParticleIndex, E> {
+                ParticleIndex::try_from(x).map_err(E::custom)
+            }
+            fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<ParticleIndex, E> {
+                text.parse().map_err(E::custom)
+            }
+        }
+        d.deserialize_any(Visitor)
+    }
+}
+
+/// The two particles of an event, smaller index first, so (a, b) and (b, a) are the same pair.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pair(ParticleIndex, ParticleIndex);
+
+impl Pair {
+    pub fn new(a: ParticleIndex, b: ParticleIndex) -> Self {
+        Pair(a.min(b), a.max(b))
+    }
+
+    pub fn first(&self) -> ParticleIndex {
+        self.0
+    }
+
+    pub fn second(&self) -> ParticleIndex {
+        self.1
+    }
+}
+
+impl fmt::Display for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {})", self.0 .0, self.1 .0)
+    }
+}
+
 #[derive(Clone, Debug, Copy)]
 pub struct Particle {
     pub p: Vector,
@@ -107,7 +226,27 @@ impl Particle {
 mod test {
     use crate::vectors::Vector;
 
-    use super::Particle;
+    use super::{IdError, Pair, Particle, ParticleIndex};
+
+    #[test]
+    fn test_particle_ids() {
+        assert_eq!("113".parse(), Ok(ParticleIndex(113)));
+        assert_eq!(" 113.0 ".parse(), Ok(ParticleIndex(113)));
+        assert_eq!("1e3".parse(), Ok(ParticleIndex(1000)));
+        assert_eq!("18446744073709551615".parse(), Ok(ParticleIndex(usize::MAX)));
+        assert_eq!("-3".parse::<ParticleIndex>(), Err(IdError::Negative(-3.0)));
+        assert_eq!("2.5".parse::<ParticleIndex>(), Err(IdError::Fractional(2.5)));
+        assert_eq!("1e300".parse::<ParticleIndex>(), Err(IdError::TooLarge(1e300)));
+        assert_eq!("inf".parse::<ParticleIndex>(), Err(IdError::TooLarge(f64::INFINITY)));
+        assert!(matches!("NaN".parse::<ParticleIndex>(), Err(IdError::NotANumber(_))));
+        assert!(matches!("x".parse::<ParticleIndex>(), Err(IdError::NotANumber(_))));
+        assert_eq!(ParticleIndex::try_from(-0.0), Ok(ParticleIndex(0)));
+
+        let (a, b) = (ParticleIndex(7), ParticleIndex(3));
+        assert_eq!(Pair::new(a, b), Pair::new(b, a));
+        assert_eq!((Pair::new(a, b).first(), Pair::new(a, b).second()), (b, a));
+        assert_eq!(Pair::new(a, b).to_string(), "(3, 7)");
+    }
 
     #[test]
     fn test_overlap_fraction() {
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use ordered_float::OrderedFloat;
+use rayon::prelude::*;
+
+use crate::csvreader::Rec;
+use crate::KeyVal;
+
+// records per rayon task while partitioning, big enough that the per chunk step lists stay cheap
+const CHUNK: usize = 1 << 14;
+
+// most global steps a run can have, every chunk keeps a list per step so a header with a silly
+// small step would otherwise take all the memory there is
+pub const MAX_STEPS: usize = 1 << 16;
+
+/// An event that has no global step to go in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepError {
+    BadLength(f64),
+    BadEnd(f64),
+    NoSteps,
+    TooManySteps(f64),
+    OutOfRange { event: usize, time: f64, steps: usize },
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepError::BadLength(length) => write!(f, "a global step can not be {} long", length),
+            StepError::BadEnd(end) => write!(f, "global steps can not end at {}", end),
+            StepError::NoSteps => write!(f, "there has to be at least one global step"),
+            StepError::TooManySteps(count) => write!(f, "{:e} global steps are more than the {} there can be", count, MAX_STEPS),
+            StepError::OutOfRange { event, time, steps } => write!(f, "event {} at t={} falls in none of the {} global steps", event, time, steps),
+        }
+    }
+}
+
+impl Error for StepError {}
+
+/// Where the global steps of a run go: `count` steps `length` long from time 0 to `end`. An event
+/// at exactly `end` goes in the last step, anything before 0 or past `end` has no step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Partitioner {
+    length: f64,
+    count: usize,
+    end: f64,
+}
+
+impl Partitioner {
+    /// Steps `length` long, as many as it takes to get to `end`.
+    pub fn with_length(length: f64, end: f64) -> Result<Self, StepError> {
+        if !(length > 0.0 && length.is_finite()) {
+            return Err(StepError::BadLength(length));
+        }
+        if !(end >= 0.0 && end.is_finite()) {
+            return Err(StepError::BadEnd(end));
+        }
+        // counted as a float first, a tiny length gives more steps than a usize holds
+        let count = (end / length).ceil().max(1.0);
+        if count > MAX_STEPS as f64 {
+            return Err(StepError::TooManySteps(count));
+        }
+        Ok(Partitioner { length, count: count as usize, end })
+    }
+
+    /// `count` steps of the same length between 0 and `end`.
+    pub fn with_count(count: usize, end: f64) -> Result<Self, StepError> {
+        if count == 0 {
+            return Err(StepError::NoSteps);
+        }
+        if count > MAX_STEPS {
+            return Err(StepError::TooManySteps(count as f64));
+        }
+        if !(end >= 0.0 && end.is_finite()) {
+            return Err(StepError::BadEnd(end));
+        }
+        // everything is at 0 when end is, any length puts it all in the first step
+        let length = if end > 0.0 { end / count as f64 } else { 1.0 };
+        Ok(Partitioner { length, count, end })
+    }
+
+    pub fn length(&self) -> f64 {
+        self.length
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The step an event at `time` goes in, None if it falls outside all of them.
+    pub fn step_of(&self, time: f64) -> Option<usize> {
+        if !(0.0..=self.end).contains(&time) {
+            return None;
+        }
+        // time/length can round up to count right at the end
+        Some(((time / self.length).floor() as usize).min(self.count - 1))
+    }
+
+    /// Sorts `recs` into the steps by their time.
+    pub fn partition(&self, recs: &[Rec]) -> Result<Partition, StepError> {
+        partition(recs, self.count, |_, rec| self.step_of(rec.time))
+    }
+}
+
+/// Events sorted into global steps, every step in the order its events had in the dump. The
+/// drivers find a pair's next event by looking further along its step, so that order matters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Partition {
+    steps: Vec<Vec<KeyVal>>,
+    heads: Vec<Vec<usize>>, // per step, see heads
+}
+
+/// Where the first event of every pair sits in `step`, going by file order and not by time. A
+/// driver starts a step from these and gets to the rest of a pair's events by looking further
+/// along the step, so a pair whose events are not in time order in the dump still has every one
+/// of them processed, as when the drivers kept a HashSet of the pairs they had pushed.
+pub fn heads(step: &[KeyVal]) -> Vec<usize> {
+    let mut seen = HashSet::new();
+    step.iter().enumerate().filter(|(_, k)| seen.insert(k.id)).map(|(i, _)| i).collect()
+}
+
+impl Partition {
+    /// Number of global steps, empty ones included.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// The events of global step `i`, `index` of each is where it sits in here.
+    pub fn step(&self, i: usize) -> &[KeyVal] {
+        &self.steps[i]
+    }
+
+    /// heads of step `i`, worked out once when the steps were.
+    pub fn heads(&self, i: usize) -> &[usize] {
+        &self.heads[i]
+    }
+
+    pub fn steps(&self) -> impl DoubleEndedIterator<Item = &[KeyVal]> + ExactSizeIterator {
+        self.steps.iter().map(Vec::as_slice)
+    }
+
+    /// Every event, step after step.
+    pub fn events(&self) -> impl DoubleEndedIterator<Item = &KeyVal> {
+        self.steps.iter().flatten()
+    }
+
+    /// Every event to change, for what gets worked out after partitioning. Moving events around
+    /// would break `index` and the heads, so only the events are handed out and not the steps.
+    #[allow(dead_code)]
+    pub fn events_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut KeyVal> {
+        self.steps.iter_mut().flatten()
+    }
+
+    pub fn par_events_mut(&mut self) -> impl ParallelIterator<Item = &mut KeyVal> {
+        self.steps.par_iter_mut().flatten()
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.steps.iter().map(Vec::len).sum()
+    }
+}
+
+impl From<Vec<Vec<KeyVal>>> for Partition {
+    /// Takes steps put together by hand, numbering every step's events from 0 again.
+    fn from(mut steps: Vec<Vec<KeyVal>>) -> Self {
+        for step in &mut steps {
+            step.iter_mut().enumerate().for_each(|(index, k)| k.index = index);
+        }
+        let heads = steps.iter().map(|step| heads(step)).collect();
+        Partition { steps, heads }
+    }
+}
+
+/// Sorts records into `steps` global steps, `step_of` gets each record's position in `recs` and
+/// the record itself. An event step_of has no step for, or one past the last step, is an error
+/// naming the first such event.
+pub fn partition<F: Fn(usize, &Rec) -> Option<usize> + Sync>(recs: &[Rec], steps: usize, step_of: F) -> Result<Partition, StepError> {
+    // each chunk sorts its own records into steps, then each step strings its pieces together in chunk order
+    let chunks: Vec<Result<Vec<Vec<Rec>>, StepError>> = recs.par_chunks(CHUNK).enumerate().map(|(c, chunk)| {
+        let mut local = vec![Vec::new(); steps];
+        for (i, rec) in chunk.iter().enumerate() {
+            let event = c * CHUNK + i;
+            match step_of(event, rec).filter(|&s| s < steps) {
+                Some(s) => local[s].push(*rec),
+                None => return Err(StepError::OutOfRange { event, time: rec.time, steps }),
+            }
+        }
+        Ok(local)
+    }).collect();
+    // in chunk order, so the error is about the first bad event
+    let chunks = chunks.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let steps: Vec<Vec<KeyVal>> = (0..steps).into_par_iter().map(|s| {
+        chunks.iter().flat_map(|local| &local[s]).enumerate().map(|(index, rec)| {
+            KeyVal { key: OrderedFloat(rec.time), val: *rec, id: rec.pair(), index, dt: f64::INFINITY }
+        }).collect()
+    }).collect();
+    let heads = steps.par_iter().map(|step| heads(step)).collect();
+    Ok(Partition { steps, heads })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::particle::{Particle, ParticleIndex};
+    use crate::vectors::Vector;
+
+    use super::*;
+
+    fn recs(n: usize) -> Vec<Rec> {
+        let p = Particle::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, 0.0), 1.0, 1.0);
+        (0..n).map(|i| Rec::new(ParticleIndex(i % 11), ParticleIndex(i % 3), &p, &p, (i * 7919 % 1000) as f64 / 100.0)).collect()
+    }
+
+    #[test]
+    fn keeps_order_within_steps() {
+        let recs = recs(3 * CHUNK + 7);
+        let data = partition(&recs, 12, |_, r| Some(r.time.floor() as usize)).unwrap();
+        assert_eq!(data.len(), 12);
+        assert_eq!(data.event_count(), recs.len());
+        for (s, step) in data.steps().enumerate() {
+            let expected: Vec<&Rec> = recs.iter().filter(|r| r.time.floor() as usize == s).collect();
+            assert_eq!(step.len(), expected.len());
+            for (i, (k, r)) in step.iter().zip(expected).enumerate() {
+                assert_eq!((k.index, k.val.time, k.id), (i, r.time, r.pair()));
+            }
+        }
+
+        // by position, like a binary dump's step table
+        let data = partition(&recs, 4, |n, _| Some(n / CHUNK)).unwrap();
+        assert_eq!(data.steps().map(<[KeyVal]>::len).collect::<Vec<_>>(), [CHUNK, CHUNK, CHUNK, 7]);
+        assert_eq!((data.step(1)[0].val.time, data.step(1)[0].val.p1), (recs[CHUNK].time, recs[CHUNK].p1));
+    }
+
+    #[test]
+    fn every_event_lands_in_a_step() {
+        let recs = recs(2 * CHUNK);
+        let max = recs.iter().map(|r| r.time).fold(0.0, f64::max);
+        assert_eq!(max, 9.99);
+
+        // a hundredth of the run, the events at exactly max included
+        let by_count = Partitioner::with_count(100, max).unwrap();
+        assert_eq!(by_count.step_of(max), Some(99));
+        assert_eq!(by_count.step_of(0.0), Some(0));
+        let data = by_count.partition(&recs).unwrap();
+        assert_eq!((data.len(), data.event_count()), (100, recs.len()));
+        assert!(data.step(99).iter().any(|k| k.val.time == max));
+
+        let by_length = Partitioner::with_length(0.5, max).unwrap();
+        assert_eq!((by_length.count(), by_length.length()), (20, 0.5));
+        assert_eq!(by_length.partition(&recs).unwrap().step(19).len(), recs.iter().filter(|r| r.time >= 9.5).count());
+        // steps that fit the run exactly, the end is still in the last one
+        assert_eq!(Partitioner::with_length(0.333, 9.99).unwrap().step_of(9.99), Some(29));
+        assert_eq!(Partitioner::with_count(7, 0.0).unwrap().step_of(0.0), Some(0));
+
+        // too short a run for the events, or events before it
+        let err = Partitioner::with_count(10, 5.0).unwrap().partition(&recs).unwrap_err();
+        let first = recs.iter().position(|r| r.time > 5.0).unwrap();
+        assert_eq!(err, StepError::OutOfRange { event: first, time: recs[first].time, steps: 10 });
+        let mut early = recs.clone();
+        early[CHUNK + 3].time = -1.0;
+        assert!(by_count.partition(&early).unwrap_err().to_string().starts_with(&format!("event {} at t=-1", CHUNK + 3)));
+        early[CHUNK + 3].time = f64::NAN;
+        assert!(by_count.partition(&early).is_err());
+        assert!(partition(&recs, 3, |n, _| Some(n % 4)).is_err());
+
+        assert_eq!(Partitioner::with_length(0.0, 1.0), Err(StepError::BadLength(0.0)));
+        assert_eq!(Partitioner::with_length(f64::NAN, 1.0).unwrap_err().to_string(), "a global step can not be NaN long");
+        assert_eq!(Partitioner::with_count(0, 1.0), Err(StepError::NoSteps));
+        assert_eq!(Partitioner::with_count(10, f64::INFINITY), Err(StepError::BadEnd(f64::INFINITY)));
+    }
+
+    #[test]
+    fn tiny_steps_are_refused() {
+        let err = Partitioner::with_length(1e-300, 1.0).unwrap_err();
+        assert!(matches!(err, StepError::TooManySteps(count) if count > 1e299), "{:?}", err);
+        assert!(err.to_string().ends_with("e299 global steps are more than the 65536 there can be"), "{}", err);
+        assert_eq!(Partitioner::with_length(1.0 / MAX_STEPS as f64, 1.0).unwrap().count(), MAX_STEPS);
+        assert!(Partitioner::with_length(0.5 / MAX_STEPS as f64, 1.0).is_err());
+        // a tiny step is fine when the run is short enough
+        assert_eq!(Partitioner::with_length(1e-300, 0.0).unwrap().count(), 1);
+        assert_eq!(Partitioner::with_count(MAX_STEPS + 1, 1.0), Err(StepError::TooManySteps((MAX_STEPS + 1) as f64)));
+    }
+
+    #[test]
+    fn renumbers_steps_put_together_by_hand() {
+        let recs = recs(6);
+        let data = partition(&recs, 2, |n, _| Some(n % 2)).unwrap();
+        let mut steps: Vec<Vec<KeyVal>> = data.steps().map(<[KeyVal]>::to_vec).collect();
+        steps[0].remove(0);
+        let data = Partition::from(steps);
+        assert_eq!(data.step(0).iter().map(|k| k.index).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(data.events().count(), 5);
+        assert_eq!(data.heads(1), [0, 1, 2]);
+    }
+
+    #[test]
+    fn heads_go_by_file_order() {
+        // the pair of 0 and 1 comes back earlier in time than it first showed up in the file
+        let mut recs = recs(8);
+        for (r, t) in recs.iter_mut().zip([0.5, 0.3, 0.2, 0.4, 0.1, 0.6, 0.7, 0.8]) {
+            r.time = t;
+        }
+        recs[4].p1 = recs[0].p1;
+        recs[4].p2 = recs[0].p2;
+        let data = partition(&recs, 1, |_, _| Some(0)).unwrap();
+        let step = data.step(0);
+        assert_eq!(step[4].id, step[0].id);
+        assert!(data.heads(0).contains(&0) && !data.heads(0).contains(&4));
+        assert_eq!(data.heads(0), heads(step));
+        let pairs: HashSet<_> = step.iter().map(|k| k.id).collect();
+        assert_eq!(data.heads(0).len(), pairs.len());
+    }
+}
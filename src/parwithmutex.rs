@@ -1,66 +1,62 @@
-use std::collections::VecDeque;
-use ordered_float::OrderedFloat;
+use std::sync::Mutex;
 use rayon::prelude::*;
-use std::sync::{Mutex,MutexGuard};
 
+use crate::{sequentialbucketqueue::HasKey, ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
 
-use crate::ParallelPriorityQueue;
-
-pub trait HasKey {
-    fn key(&self) -> OrderedFloat<f64>;
-}
-
+/// Bucket queue with a lock on every bucket, so bulk_push can fill the buckets from all threads at
+/// once. Unlike ParBqueue, `start` and `len` are plain fields that only move in `&mut self` methods,
+/// once the parallel part of a bulk_push is over. No atomics, but nobody can push into it while a
+/// bucket is being processed either, so bulk_process collects the successors and pushes them after.
 #[derive(Debug)]
-pub struct ParaBqueue<T:Copy + PartialOrd + Sized + Send>{
+pub struct ParaBqueue<T>{
     bucketwidth: f64,
     len: usize,
     data: Vec<Mutex<Vec<T>>>,
     start: usize
 }
 
-impl<'a, T:Copy + PartialOrd + HasKey + Send + Sync> ParaBqueue<&'a T> {
+#[allow(dead_code)]
+impl<'a, T:PartialOrd + HasKey + Send + Sync> ParaBqueue<&'a T> {
     pub fn new(bucketnum: usize, bucketwidth: f64) -> Self {
-        let mut datas:Vec<Mutex<Vec<&'a T>>> = Vec::with_capacity(bucketnum);
-        (0..bucketnum).into_iter().for_each(|_i| {
-            datas.push(Mutex::new(Vec::new()));
-        });
         return Self {
             len: 0,
             start: bucketnum,
             bucketwidth,
-            data: datas
+            data: (0..bucketnum).map(|_| Mutex::new(Vec::new())).collect()
         }
     }
 
+    fn index(&self, elem: &T) -> usize {
+        (elem.key()/self.bucketwidth).floor() as usize
+    }
+
     pub fn push(&mut self, elem: &'a T) {
-        let index = (elem.key()/self.bucketwidth).floor() as usize;
-        self.data[index].lock().unwrap().push(elem);
+        let index = self.index(elem);
+        self.data[index].get_mut().unwrap().push(elem);
         self.len += 1;
         if index < self.start {
             self.start = index;
         }
     }
 
-    pub fn pop(&mut self) -> Option<&T>{
+    /// Any element of the first bucket, the last one pushed into it.
+    pub fn pop(&mut self) -> Option<&'a T>{
         if self.is_empty() {
             return None
         } else {
-            let y = self.data[self.start].lock().unwrap().pop();
+            let y = self.data[self.start].get_mut().unwrap().pop();
             self.len -= 1;
-            while self.start < self.data.len() && self.data[self.start].lock().unwrap().is_empty() {
-                self.start = self.start +1;
-            }
+            self.advance_start();
             return y
         }
     }
 
-   
-
-    pub fn peek(&self) -> Option<&T> {
+    /// What pop would hand out.
+    pub fn peek(&self) -> Option<&'a T> {
         if self.is_empty() {
             return None
         } else {
-            return Some(self.data[self.start].lock().unwrap()[0]);
+            return self.data[self.start].lock().unwrap().last().copied();
         }
     }
 
@@ -68,59 +64,120 @@ impl<'a, T:Copy + PartialOrd + HasKey + Send + Sync> ParaBqueue<&'a T> {
         return self.start >= self.data.len();
     }
 
-    // If we don't want this method, this version can get rid of the len field completely.
     pub fn len(&self) -> usize {
         return self.len;
     }
 
-    fn bulk_push<I: ParallelIterator<Item = &'a T>>(&mut self, elems: I) {
-        let mut mindex = self.data.len();
-        elems.for_each(|i| {
-            let index = (i.key()/self.bucketwidth).floor() as usize;
-            if index < mindex {
-                mindex = index;
-            }
-            self.data[index].lock().unwrap().push(i);
-        });
-        if self.start > mindex {
-            self.start = mindex;
+    /// Empties every bucket without giving back their memory.
+    pub fn clear(&mut self) {
+        for bucket in self.data.iter_mut() {
+            bucket.get_mut().unwrap().clear();
         }
+        self.start = self.data.len();
+        self.len = 0;
+    }
 
+    /// Removes every element with a key before `time`, whole buckets first and then whatever
+    /// part of the bucket `time` falls in.
+    pub fn drain_until(&mut self, time: f64) -> Vec<&'a T> {
+        let mut ret = Vec::new();
+        let end = ((time/self.bucketwidth).floor().max(0.0) as usize).min(self.data.len());
+        for bucket in self.data[self.start.min(end)..end].iter_mut() {
+            ret.append(bucket.get_mut().unwrap());
+        }
+        if end < self.data.len() {
+            let bucket = self.data[end].get_mut().unwrap();
+            ret.extend(bucket.iter().filter(|e| e.key().0 < time));
+            bucket.retain(|e| e.key().0 >= time);
+        }
+        self.len -= ret.len();
+        self.advance_start();
+        ret
     }
 
-    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a T> {
-        self.data.push(Mutex::new(Vec::new()));
-        let bucket = self.data.swap_remove(self.start).into_inner().unwrap();
-        self.len = self.len - bucket.len();
-        while self.start < self.data.len() && self.data[self.start].lock().unwrap().is_empty() {
-              self.start = self.start +1;
+    pub fn bulk_push<I: ParallelIterator<Item = &'a T>>(&mut self, elems: I) {
+        let queue = &*self;
+        // how many went in and the lowest bucket any of them went to, start and len move after
+        let (count, lowest) = elems.map(|e| {
+            let index = queue.index(e);
+            queue.data[index].lock().unwrap().push(e);
+            (1, index)
+        }).reduce(|| (0, usize::MAX), |a, b| (a.0 + b.0, a.1.min(b.1)));
+        self.len += count;
+        self.start = self.start.min(lowest);
+    }
+
+    /// Swaps the first bucket with `buf`, so the bucket keeps `buf`'s allocation.
+    pub fn bulk_pop_into(&mut self, buf: &mut Vec<&'a T>) {
+        buf.clear();
+        if self.is_empty() {
+            return;
         }
-        return bucket.into_iter();
+        std::mem::swap(self.data[self.start].get_mut().unwrap(), buf);
+        self.len -= buf.len();
+        self.advance_start();
+    }
+
+    pub fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a T> {
+        let mut bucket = Vec::new();
+        self.bulk_pop_into(&mut bucket);
+        bucket.into_par_iter()
     }
 
+    pub fn bulk_process<F: Fn(&'a T) -> Option<&'a T> + Sync + Send>(&mut self, f: F) {
+        let mut bucket = Vec::new();
+        self.bulk_pop_into(&mut bucket);
+        let next: Vec<&'a T> = bucket.par_iter().filter_map(|e| f(e)).collect();
+        self.bulk_push(next.into_par_iter());
+    }
 
+    fn advance_start(&mut self) {
+        while self.start < self.data.len() && self.data[self.start].get_mut().unwrap().is_empty() {
+            self.start += 1;
+        }
+    }
 }
 
-impl <'a, E: Copy + Ord + HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for ParaBqueue<&'a E> {
+impl <'a, E: Ord + HasKey + Send + Sync> SeqentialPriorityQueue<'a, E> for ParaBqueue<&'a E> {
     fn push(&mut self, e: &'a E) {
         ParaBqueue::push(self, e);
     }
-    fn pop(&mut self) -> Option<&E> {
+    fn pop(&mut self) -> Option<&'a E> {
         ParaBqueue::pop(self)
     }
+    fn peek(&self) -> Option<&'a E> {
+        ParaBqueue::peek(self)
+    }
     fn is_empty(&self) -> bool {
         ParaBqueue::is_empty(self)
     }
-    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) -> i32 {
+    fn len(&self) -> usize {
+        ParaBqueue::len(self)
+    }
+    fn clear(&mut self) {
+        ParaBqueue::clear(self)
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        ParaBqueue::drain_until(self, time)
+    }
+    fn pop_order(&self) -> PopOrder {
+        PopOrder::Bucketed { width: self.bucketwidth, sorted: false }
+    }
+}
 
+impl <'a, E: Ord + HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for ParaBqueue<&'a E> {
+    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
+        ParaBqueue::bulk_process(self, f);
     }
     fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
         ParaBqueue::bulk_push(self, es);
     }
-
     fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
         ParaBqueue::bulk_pop(self)
     }
+    fn bulk_pop_into(&mut self, buf: &mut Vec<&'a E>) {
+        ParaBqueue::bulk_pop_into(self, buf)
+    }
 }
 
 #[cfg(test)]
@@ -130,12 +187,7 @@ mod tests {
 
     use super::*;
 
-    impl HasKey for f64 {
-        fn key(&self) -> OrderedFloat<f64> {
-            OrderedFloat(*self)
-        }
-    }
-    
+
     #[test]
     fn it_works() {
         let max = 500;
@@ -217,7 +269,7 @@ mod tests {
         sortvec.sort_by(|a, b| a.partial_cmp(b).unwrap());
 
         
-        heap1.bulk_push(vector.iter());     
+        heap1.bulk_push(vector.par_iter());     
         assert_eq!(heap1.len(), total);
 
         while !heap1.is_empty() {
@@ -225,7 +277,7 @@ mod tests {
             sortvec.retain(|i| {
                 ((i.key()/heap1.bucketwidth).floor() as usize) > ((min.key()/heap1.bucketwidth).floor() as usize)
             });
-            let bulkpopped = heap1.bulk_pop();
+            let bulkpopped: Vec<&f64> = heap1.bulk_pop().collect();
             for pop in bulkpopped {
                 assert_eq!(pop.floor(), min.floor());
             }
@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+use crate::csvreader::{Rec, FIELDS};
+use crate::particle::ParticleIndex;
+
+// every line we care about starts with this, anything else in a dump is log chatter
+const EVENT_PREFIX: &str = "Event:";
+
+/// Parses one line of a raw dump. Lines that are not events give None. Events come with either
+/// just `p1 p2 time` or with both particles' position, velocity and radius on top, and then maybe
+/// both masses too. The short kind has no particle state, so all of it reads as zero, and masses
+/// that are not there are NaN. The ids have to be whole numbers, `113` or `113.0`.
+pub fn parse_line(line: &str) -> Option<Result<Rec, Box<dyn Error>>> {
+    let fields = line.trim_start().strip_prefix(EVENT_PREFIX)?;
+    Some(parse_fields(fields))
+}
+
+fn parse_fields(fields: &str) -> Result<Rec, Box<dyn Error>> {
+    let mut ids = [ParticleIndex(0); 2];
+    let mut v = [0.0; FIELDS - 2];
+    v[15] = f64::NAN;
+    v[16] = f64::NAN;
+    let mut n = 0;
+    for field in fields.split_whitespace() {
+        if n == FIELDS {
+            return Err(format!("more than {} columns", FIELDS).into());
+        }
+        if n < ids.len() {
+            ids[n] = field.parse().map_err(|e| format!("column {}: {}", n + 1, e))?;
+        } else {
+            v[n - ids.len()] = field.parse().map_err(|e| format!("column {} `{}`: {}", n + 1, field, e))?;
+        }
+        n += 1;
+    }
+    if n != 3 && n != 17 && n != FIELDS {
+        return Err(format!("expected 3, 17 or {} columns, found {}", FIELDS, n).into());
+    }
+    Ok(Rec::from_values(ids[0], ids[1], &v))
+}
+
+/// An event and the line of the dump it is on.
+pub type Numbered = (u64, Result<Rec, Box<dyn Error>>);
+
+/// The events of a raw dump one at a time with the line each is on, counting from 1. Only failing
+/// to read the dump at all is an error of the iterator, a malformed event line comes with its line
+/// like any other and reading carries on after it.
+pub fn numbered_events<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Numbered>> {
+    reader.lines().enumerate().filter_map(|(i, line)| match line {
+        Ok(line) => parse_line(&line).map(|r| Ok((i as u64 + 1, r))),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// The events of a raw dump one at a time, in file order, reading only as far as asked. A
+/// malformed event line is an error naming the line, it does not get skipped.
+pub fn events<R: BufRead>(reader: R) -> impl Iterator<Item = Result<Rec, Box<dyn Error>>> {
+    numbered_events(reader).map(|event| match event {
+        Ok((line, r)) => r.map_err(|e| format!("line {}: {}", line, e).into()),
+        Err(e) => Err(e.into()),
+    })
+}
+
+/// Reads every event out of a raw dump, in file order.
+pub fn read_events<R: BufRead>(reader: R) -> Result<VecDeque<Rec>, Box<dyn Error>> {
+    events(reader).collect()
+}
+
+/// Writes records as `Event:` lines with the 17 columns readme.txt describes, and the masses after
+/// them for events that know them. Ids are written as integers, floats the shortest way that reads
+/// back to the same bits.
+pub fn write_events<'r, W: Write>(w: &mut W, recs: impl IntoIterator<Item = &'r Rec>) -> io::Result<()> {
+    for rec in recs {
+        write!(w, "{} {} {}", EVENT_PREFIX, rec.p1.0, rec.p2.0)?;
+        let columns = if rec.p1m.is_nan() && rec.p2m.is_nan() { 17 } else { FIELDS };
+        for v in &rec.values()[..columns - 2] {
+            write!(w, " {:?}", v)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+/// The raw dump counterpart of csvreader::csvcon, so a dump no longer has to go through
+/// python/tocsv.py or scala/eventsToCsv.scala first.
+pub fn rawcon(file: &str) -> Result<VecDeque<Rec>, Box<dyn Error>> {
+    let file_path = file.replace("\"","").replace("\\","/");
+    read_events(BufReader::new(File::open(file_path.trim())?))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn reads_both_layouts() {
+        let dump = "\
+Starting run with 400 particles
+Event: 113.0 137 2.5e-05 0.61 0.72 -0.36 0.0006 -0.0001 3.0e-05 0.001 0.62 0.72 -0.36 0.0009 -3.0e-05 -0.0002 0.001
+step 1 done
+Event:   375   126 0.00083
+  Event: 1 2 3
+";
+        let recs = read_events(dump.as_bytes()).unwrap();
+        assert_eq!(recs.len(), 3);
+        assert_eq!((recs[0].p1, recs[0].p2, recs[0].time), (ParticleIndex(113), ParticleIndex(137), 2.5e-05));
+        assert_eq!((recs[0].p1x, recs[0].p1r, recs[0].p2vz, recs[0].p2r), (0.61, 0.001, -0.0002, 0.001));
+        assert_eq!((recs[1].p1, recs[1].p2, recs[1].time), (ParticleIndex(375), ParticleIndex(126), 0.00083));
+        assert_eq!((recs[1].p1x, recs[1].p2r), (0.0, 0.0));
+        assert_eq!(recs[2].time, 3.0);
+    }
+
+    #[test]
+    fn write_then_read_is_lossless() {
+        let mut recs = crate::csvreader::tests::awkward();
+        recs[0].p1m = f64::NAN;
+        recs[0].p2m = f64::NAN;
+        recs[1].p1m = f64::NAN;
+        let mut out = Vec::new();
+        write_events(&mut out, &recs).unwrap();
+        let columns: Vec<usize> = String::from_utf8_lossy(&out).lines().map(|l| l.strip_prefix("Event: ").unwrap().split(' ').count()).collect();
+        assert_eq!(columns[..3], [17, FIELDS, FIELDS]);
+        let back: Vec<Rec> = read_events(out.as_slice()).unwrap().into();
+        assert_eq!(bytemuck::cast_slice::<Rec, u8>(&back), bytemuck::cast_slice::<Rec, u8>(&recs));
+    }
+
+    #[test]
+    fn names_the_bad_line() {
+        assert!(parse_line("Particles: 1 2 3").is_none());
+        assert!(parse_line("Event: 1 2").unwrap().is_err());
+        assert!(parse_line(&format!("Event:{}", " 1".repeat(18))).unwrap().is_err());
+
+        let err = read_events("header\nEvent: 1 2 0.5\nEvent: 1 x 0.5\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("line 3: column 2: particle id `x`"), "{}", err);
+        let err = read_events("Event: 1 2 0.5\nEvent: 4 2.5 0.5\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: column 2: particle id 2.5 is not a whole number");
+        let err = read_events("Event: 1 2 y\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("line 1: column 3 `y`"), "{}", err);
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::VecDeque;
 use ordered_float::OrderedFloat;
 
-use crate::SeqentialPriorityQueue;
+use crate::{PopOrder, SeqentialPriorityQueue};
+use crate::tuning::{self, Retuner};
 
 pub trait HasKey {
     fn key(&self) -> OrderedFloat<f64>;
@@ -11,31 +12,74 @@ pub trait HasKey {
 pub struct Bqueue<T:Copy + PartialOrd>{
     bucketwidth: f64,
     data: Vec<VecDeque<T>>,
-    start: usize
+    start: usize,
+    len: usize,
+    retuner: Option<Retuner>,
+    popped: usize // how many elements came out of the current start bucket, fed to the retuner
 }
 
 impl<'a, T:PartialOrd + HasKey> Bqueue<&'a T> {
     pub fn new(bucketnum: usize, bucketwidth: f64) -> Self {
         return Self {
             start: bucketnum,
+            len: 0,
             bucketwidth,
-            data: vec![VecDeque::new();bucketnum]
+            data: vec![VecDeque::new();bucketnum],
+            retuner: None,
+            popped: 0
         }
     }
 
+    #[allow(dead_code)]
+    /// Same as new, but re-buckets in place whenever the retuner sees the popped buckets drift off target.
+    pub fn with_retuner(bucketnum: usize, bucketwidth: f64, retuner: Retuner) -> Self {
+        let mut q = Self::new(bucketnum, bucketwidth);
+        q.retuner = Some(retuner);
+        q
+    }
+
+    #[allow(dead_code)]
+    pub fn bucketwidth(&self) -> f64 {
+        self.bucketwidth
+    }
+
+    /// Moves every element into buckets of the new width, keeping the same key horizon. The width
+    /// moves at most `tuning::MAX_RETUNE` times either way, however far off the asked for one is.
+    /// Elements that shared a bucket keep their relative order.
+    pub fn rebucket(&mut self, bucketwidth: f64) {
+        let (bucketwidth, bucketnum) = tuning::retuned(self.data.len(), self.bucketwidth, bucketwidth);
+        let old = std::mem::replace(&mut self.data, vec![VecDeque::new();bucketnum]);
+        self.bucketwidth = bucketwidth;
+        self.start = bucketnum;
+        self.len = 0;
+        self.popped = 0;
+        for elem in old.into_iter().flatten() {
+            self.push(elem);
+        }
+    }
+
+    /// Buckets are kept sorted, so elements come out in exact order and equal ones in the order they went in.
     pub fn push(&mut self, elem: &'a T) {
         let index = (elem.key()/self.bucketwidth).floor() as usize;
-        self.data[index].push_back(elem);
+        let bucket = &mut self.data[index];
+        let at = bucket.partition_point(|e| *e <= elem);
+        bucket.insert(at, elem);
+        self.len += 1;
         if index < self.start {
             self.start = index;
         }
     }
 
-    pub fn pop(&mut self) -> Option<&T>{
+    pub fn pop(&mut self) -> Option<&'a T>{
         if self.is_empty() {
             return None
         } else {
             let y = self.data[self.start].pop_front();
+            self.len -= 1;
+            self.popped += 1;
+            if self.data[self.start].is_empty() {
+                self.finish_bucket();
+            }
             while self.start < self.data.len() && self.data[self.start].is_empty() {
                 self.start = self.start +1;
             }
@@ -43,7 +87,7 @@ impl<'a, T:PartialOrd + HasKey> Bqueue<&'a T> {
         }
     }
 
-    pub fn peek(&self) -> Option<&T> {
+    pub fn peek(&self) -> Option<&'a T> {
         if self.is_empty() {
             return None
         } else {
@@ -54,18 +98,88 @@ impl<'a, T:PartialOrd + HasKey> Bqueue<&'a T> {
     pub fn is_empty(&self) -> bool {
         return self.start >= self.data.len();
     }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Empties every bucket without giving back their memory.
+    pub fn clear(&mut self) {
+        for bucket in self.data.iter_mut() {
+            bucket.clear();
+        }
+        self.start = self.data.len();
+        self.len = 0;
+        self.popped = 0;
+    }
+
+    /// Empties the queue for a new run over `bucketnum` buckets of `bucketwidth`, reusing the buckets
+    /// (and whatever they had allocated) from the last one.
+    #[allow(dead_code)]
+    pub fn reset(&mut self, bucketnum: usize, bucketwidth: f64) {
+        self.clear();
+        self.data.resize_with(bucketnum, VecDeque::new);
+        self.bucketwidth = bucketwidth;
+        self.start = bucketnum;
+    }
+
+    /// Removes every element with a key before `time`, whole buckets first and then whatever
+    /// part of the bucket `time` falls in.
+    pub fn drain_until(&mut self, time: f64) -> Vec<&'a T> {
+        let mut ret = Vec::new();
+        let end = ((time/self.bucketwidth).floor().max(0.0) as usize).min(self.data.len());
+        for bucket in self.data[self.start.min(end)..end].iter_mut() {
+            ret.extend(bucket.drain(..));
+        }
+        if end < self.data.len() {
+            let mut keep = VecDeque::new();
+            for elem in self.data[end].drain(..) {
+                if elem.key().0 < time { ret.push(elem) } else { keep.push_back(elem) }
+            }
+            self.data[end] = keep;
+        }
+        self.len -= ret.len();
+        while self.start < self.data.len() && self.data[self.start].is_empty() {
+            self.start += 1;
+        }
+        ret
+    }
+
+    // called when the start bucket runs dry, this is where a drifting width gets corrected
+    fn finish_bucket(&mut self) {
+        let popped = std::mem::take(&mut self.popped);
+        let suggestion = self.retuner.as_mut().and_then(|r| r.observe(popped, self.bucketwidth));
+        if let Some(width) = suggestion {
+            self.rebucket(width);
+        }
+    }
 }
 
 impl <'a, E: Ord + HasKey> SeqentialPriorityQueue<'a, E> for Bqueue<&'a E> {
     fn push(&mut self, e: &'a E) {
         Bqueue::push(self, e);
     }
-    fn pop(&mut self) -> Option<&E> {
+    fn pop(&mut self) -> Option<&'a E> {
         Bqueue::pop(self)
     }
+    fn peek(&self) -> Option<&'a E> {
+        Bqueue::peek(self)
+    }
     fn is_empty(&self) -> bool {
         Bqueue::is_empty(self)
     }
+    fn len(&self) -> usize {
+        Bqueue::len(self)
+    }
+    fn clear(&mut self) {
+        Bqueue::clear(self)
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        Bqueue::drain_until(self, time)
+    }
+    fn pop_order(&self) -> PopOrder {
+        PopOrder::Bucketed { width: self.bucketwidth, sorted: true }
+    }
 }
 
 #[cfg(test)]
@@ -149,4 +263,83 @@ mod tests {
         assert_eq!(heap1.is_empty(), true);
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rebucket_keeps_elements() {
+        let values: Vec<f64> = (0..200).map(|i| i as f64 * 0.37).collect();
+        let mut heap1: Bqueue<&f64> = Bqueue::with_retuner(100, 1.0, Retuner::new(8.0, 1.5, 2));
+        for v in &values {
+            heap1.push(v);
+        }
+        let mut popped = Vec::new();
+        while let Some(v) = heap1.pop() {
+            popped.push(*v);
+        }
+        // the retuner should have widened the buckets towards ~8 per bucket
+        assert!(heap1.bucketwidth() > 1.0);
+        assert_eq!(popped.len(), values.len());
+        for w in popped.windows(2) {
+            assert!(w[0] <= w[1]);
+        }
+
+        heap1.push(&values[3]);
+        heap1.push(&values[150]);
+        heap1.rebucket(0.5);
+        assert_eq!(heap1.pop(), Some(&values[3]));
+        assert_eq!(heap1.pop(), Some(&values[150]));
+        assert!(heap1.is_empty());
+    }
+
+    #[test]
+    fn narrowing_retune_is_bounded() {
+        // every element in one bucket, so once it runs dry the retuner asks for a width ten thousand times narrower
+        let values: Vec<f64> = (0..10000).map(|i| 0.5 + i as f64 * 1e-5).collect();
+        let mut heap1: Bqueue<&f64> = Bqueue::with_retuner(10, 1.0, Retuner::new(1.0, 1.5, 1));
+        for v in &values {
+            heap1.push(v);
+        }
+        for v in &values {
+            assert_eq!(heap1.pop(), Some(v));
+        }
+        assert!(heap1.is_empty());
+        assert_eq!(heap1.bucketwidth(), 1.0 / tuning::MAX_RETUNE);
+        assert!(heap1.data.len() <= 10 * tuning::MAX_RETUNE as usize + 1);
+
+        heap1.push(&values[7]);
+        heap1.rebucket(0.0);
+        heap1.rebucket(f64::NAN);
+        heap1.rebucket(1e-300);
+        assert!(heap1.bucketwidth() > 0.0 && heap1.data.len() < 1 << 20);
+        assert_eq!(heap1.pop(), Some(&values[7]));
+    }
+
+    #[test]
+    fn drain_until_and_clear() {
+        let values: Vec<f64> = (0..100).map(|i| i as f64 * 0.3).collect();
+        let mut heap1: Bqueue<&f64> = Bqueue::new(40, 1.0);
+        for v in &values {
+            heap1.push(v);
+        }
+        assert_eq!(heap1.len(), 100);
+        let mut drained = heap1.drain_until(10.6);
+        drained.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(drained, values[..36].iter().collect::<Vec<_>>());
+        assert_eq!(heap1.len(), 64);
+        assert_eq!(heap1.pop(), Some(&values[36]));
+
+        heap1.clear();
+        assert!(heap1.is_empty());
+        assert_eq!(heap1.len(), 0);
+        assert_eq!(heap1.pop(), None);
+        heap1.push(&values[5]);
+        assert_eq!(heap1.peek(), Some(&values[5]));
+
+        heap1.reset(80, 0.5);
+        assert!(heap1.is_empty());
+        assert!(heap1.data[3].capacity() > 0);
+        heap1.push(&values[5]);
+        heap1.push(&values[2]);
+        assert_eq!(heap1.pop(), Some(&values[2]));
+        assert_eq!(heap1.pop(), Some(&values[5]));
+    }
+}
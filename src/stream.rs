@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+
+use ordered_float::OrderedFloat;
+
+use crate::bindump::{self, DumpFile};
+use crate::csvreader::{self, Rec};
+use crate::partition::{StepError, MAX_STEPS};
+use crate::rawreader;
+use crate::KeyVal;
+
+/// Events of a dump in file order, read as they are asked for.
+pub type Events = Box<dyn Iterator<Item = Result<Rec, Box<dyn Error>>>>;
+
+/// Whether `path` names a csv dump rather than a raw one.
+pub fn is_csv(path: &str) -> bool {
+    path.trim().trim_end_matches('"').ends_with(".csv")
+}
+
+/// Opens any dump main can load, without loading it: binary dumps by their magic, csv by the
+/// extension and anything else as a raw `Event:` dump. Only the record being looked at is held
+/// in memory, for binary dumps the OS pages the mapping in and out as it likes.
+pub fn open(path: &str) -> Result<Events, Box<dyn Error>> {
+    let path = path.replace("\"","").replace("\\","/");
+    let path = path.trim();
+    if bindump::is_dump(path) {
+        let dump = DumpFile::open(path)?;
+        Ok(Box::new((0..dump.len()).map(move |i| Ok(dump.records()[i]))))
+    } else if is_csv(path) {
+        Ok(Box::new(csvreader::records(path)?))
+    } else {
+        Ok(Box::new(rawreader::events(BufReader::new(File::open(path)?))))
+    }
+}
+
+/// Cuts a stream of events into global steps `step` long, the same cut main makes with
+/// `time/step`, only without knowing where the dump ends. A step is read once the one before it
+/// has been handed out, so at most one step plus one event is in memory. Steps nothing falls in
+/// come out empty so step numbers stay put. The dumps are in time order, an event that belongs to
+/// a step already handed out goes with the current one.
+///
+/// Events come out with an infinite `dt`, working it out needs the masses and those are up to
+/// whoever reads the steps.
+pub struct Steps<I> {
+    events: I,
+    step: f64,
+    next: usize,
+    pending: Option<Rec>,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Result<Rec, Box<dyn Error>>>> Steps<I> {
+    /// Refuses steps that are not a positive length, like Partitioner::with_length does. A step of
+    /// 0 would put every event in a step of its own past the end of time.
+    pub fn new(events: I, step: f64) -> Result<Self, StepError> {
+        if !(step > 0.0 && step.is_finite()) {
+            return Err(StepError::BadLength(step));
+        }
+        Ok(Steps { events, step, next: 0, pending: None, done: false })
+    }
+}
+
+impl<I: Iterator<Item = Result<Rec, Box<dyn Error>>>> Iterator for Steps<I> {
+    type Item = Result<Vec<KeyVal>, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut out = Vec::new();
+        loop {
+            let rec = match self.pending.take().map(Ok).or_else(|| self.events.next()) {
+                Some(Ok(rec)) => rec,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.done = true;
+                    break;
+                }
+            };
+            // same cap as Partitioner, past it the empty steps in between would never run out
+            let step = (rec.time/self.step).floor();
+            if step >= MAX_STEPS as f64 {
+                return Some(Err(Box::new(StepError::TooManySteps(step + 1.0))));
+            }
+            if step as usize > self.next {
+                self.pending = Some(rec);
+                break;
+            }
+            let index = out.len();
+            out.push(KeyVal { key: OrderedFloat(rec.time), val: rec, id: rec.pair(), index, dt: f64::INFINITY });
+        }
+        if self.done && out.is_empty() {
+            return None;
+        }
+        self.next += 1;
+        Some(Ok(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::particle::{Pair, Particle, ParticleIndex};
+    use crate::vectors::Vector;
+
+    use super::*;
+
+    fn rec(p1: u32, p2: u32, time: f64) -> Rec {
+        let p = Particle::new(Vector::new(p1 as f64, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 0.5, 1.0);
+        Rec::new(ParticleIndex(p1 as usize), ParticleIndex(p2 as usize), &p, &p, time)
+    }
+
+    fn times(steps: Vec<Vec<KeyVal>>) -> Vec<Vec<f64>> {
+        steps.into_iter().map(|s| s.into_iter().map(|k| k.val.time).collect()).collect()
+    }
+
+    #[test]
+    fn cuts_into_steps() {
+        let recs = vec![rec(1, 2, 0.1), rec(3, 4, 0.5), rec(1, 2, 1.2), rec(5, 6, 0.9), rec(2, 1, 3.5)];
+        let steps: Vec<Vec<KeyVal>> = Steps::new(recs.into_iter().map(Ok), 1.0).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(times(steps.clone()), vec![vec![0.1, 0.5], vec![1.2, 0.9], vec![], vec![3.5]]);
+        assert_eq!(steps[1][1].index, 1);
+        assert_eq!(steps[3][0].id, steps[0][0].id);
+        assert_eq!(steps[3][0].id, Pair::new(ParticleIndex(1), ParticleIndex(2)));
+
+        assert_eq!(Steps::new(std::iter::empty(), 1.0).unwrap().count(), 0);
+
+        for step in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(Steps::new(std::iter::empty(), step).err().map(|e| e.to_string()), Some(StepError::BadLength(step).to_string()));
+        }
+    }
+
+    #[test]
+    fn stops_at_a_bad_event() {
+        let events: Vec<Result<Rec, Box<dyn Error>>> = vec![Ok(rec(1, 2, 0.1)), Err("line 2: bad".into()), Ok(rec(1, 2, 0.2))];
+        let mut steps = Steps::new(events.into_iter(), 1.0).unwrap();
+        assert!(steps.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn stops_at_too_many_steps() {
+        let recs = vec![rec(1, 2, 0.0), rec(3, 4, 1.0)];
+        let mut steps = Steps::new(recs.into_iter().map(Ok), 1e-300).unwrap();
+        // the first step only ends at the event past the cap, so it never gets handed out
+        assert!(steps.next().unwrap().unwrap_err().to_string().ends_with("global steps are more than the 65536 there can be"));
+    }
+
+    #[test]
+    fn every_format_streams_the_same() {
+        let recs = vec![rec(1, 2, 0.1), rec(3, 4, 0.5), rec(1, 2, 1.2)];
+        let base = std::env::temp_dir().join(format!("queue_test_{}_stream", std::process::id()));
+        let base = base.to_string_lossy();
+
+        let mut csv = String::from("# step=1\n") + &crate::csvreader::COLUMNS.join(",") + "\n";
+        let mut raw = String::from("# step=1\nrun started\n");
+        for r in &recs {
+            let cols: Vec<String> = [r.p1.0, r.p2.0].iter().map(|i| i.to_string()).chain(r.values().iter().map(|x| x.to_string())).collect();
+            csv += &(cols.join(",") + "\n");
+            raw += &format!("Event: {}\n", cols.join(" "));
+        }
+        std::fs::write(format!("{}.csv", base), csv).unwrap();
+        std::fs::write(format!("{}.dump", base), raw).unwrap();
+        bindump::write_dump(&mut File::create(format!("{}.qtev", base)).unwrap(), &recs, &crate::metadata::Metadata::new()).unwrap();
+
+        for ext in ["csv", "dump", "qtev"] {
+            let read: Vec<Rec> = open(&format!("{}.{}", base, ext)).unwrap().collect::<Result<_, _>>().unwrap();
+            assert_eq!(bytemuck::cast_slice::<Rec, u8>(&read), bytemuck::cast_slice::<Rec, u8>(&recs), "{}", ext);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use rayon::prelude::*;
+
+use crate::{sequentialbucketqueue::HasKey, ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
+
+/// Hierarchical timing wheel. Keys are cut into ticks of `tickwidth`, the finest wheel has one slot
+/// per tick and every wheel above it has slots 2^`bits` times wider than the one below. An element sits
+/// on the wheel of the highest digit where its tick differs from the current one, and gets cascaded
+/// down when the current tick reaches its slot. Ticks further out than all wheels can reach wait in an
+/// overflow list, so unlike Bqueue nothing is allocated in proportion to the horizon.
+///
+/// The current slot of the finest wheel is the unit bulk_pop hands out, the same as one bucket of a
+/// Bqueue with `tickwidth` buckets. Elements inside a slot come out smallest first.
+#[derive(Debug)]
+pub struct TimingWheel<T> {
+    tickwidth: f64,
+    bits: u32,
+    now: u64,               // current tick, only ever moves forward
+    wheels: Vec<Vec<Vec<T>>>,
+    occupied: Vec<u64>,     // bit s set when slot s of that wheel is non-empty
+    overflow: Vec<T>,
+    len: usize,
+}
+
+#[allow(dead_code)]
+impl<'a, T: PartialOrd + HasKey> TimingWheel<&'a T> {
+    /// `bits` is at most 6 so that a wheel's occupancy fits in one u64.
+    pub fn new(tickwidth: f64, bits: u32, wheelnum: usize) -> Self {
+        let bits = bits.clamp(1, 6);
+        let wheelnum = wheelnum.clamp(1, 64_usize.div_ceil(bits as usize));
+        TimingWheel {
+            tickwidth,
+            bits,
+            now: 0,
+            wheels: (0..wheelnum).map(|_| (0..1 << bits).map(|_| Vec::new()).collect()).collect(),
+            occupied: vec![0; wheelnum],
+            overflow: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn tick(&self, elem: &T) -> u64 {
+        (elem.key().0 / self.tickwidth).floor().max(0.0) as u64
+    }
+
+    fn digit(&self, tick: u64, wheel: usize) -> usize {
+        ((tick >> (self.bits as usize * wheel)) & ((1 << self.bits) - 1)) as usize
+    }
+
+    // ticks already behind the current one are treated as due now
+    fn place(&mut self, elem: &'a T) {
+        let tick = self.tick(elem).max(self.now);
+        let diff = tick ^ self.now;
+        let wheel = if diff == 0 { 0 } else { (63 - diff.leading_zeros()) as usize / self.bits as usize };
+        if wheel >= self.wheels.len() {
+            self.overflow.push(elem);
+        } else {
+            let slot = self.digit(tick, wheel);
+            self.wheels[wheel][slot].push(elem);
+            self.occupied[wheel] |= 1 << slot;
+        }
+    }
+
+    // first occupied slot of a wheel that is still ahead of the current tick
+    fn next_slot(&self, wheel: usize) -> Option<usize> {
+        let current = self.digit(self.now, wheel);
+        let ahead = if wheel == 0 { !0u64 << current } else { !0u64 << current << 1 };
+        let bits = self.occupied[wheel] & ahead;
+        (bits != 0).then(|| bits.trailing_zeros() as usize)
+    }
+
+    fn take_slot(&mut self, wheel: usize, slot: usize) -> Vec<&'a T> {
+        self.occupied[wheel] &= !(1 << slot);
+        std::mem::take(&mut self.wheels[wheel][slot])
+    }
+
+    /// Turns the wheels until the current finest slot holds something, cascading coarser slots down
+    /// on the way. Does nothing when the current slot is already non-empty or the wheel is empty.
+    fn advance(&mut self) {
+        while self.len > 0 && self.wheels[0][self.digit(self.now, 0)].is_empty() {
+            let found = (0..self.wheels.len()).find_map(|w| self.next_slot(w).map(|s| (w, s)));
+            match found {
+                Some((wheel, slot)) => {
+                    // keep the digits above this wheel, jump to the start of the slot
+                    let shift = self.bits as usize * wheel;
+                    let above = if shift + (self.bits as usize) >= 64 { 0 } else { self.now >> (shift + self.bits as usize) << (shift + self.bits as usize) };
+                    self.now = above | ((slot as u64) << shift);
+                    if wheel > 0 {
+                        for elem in self.take_slot(wheel, slot) {
+                            self.place(elem);
+                        }
+                    }
+                }
+                None => {
+                    let waiting = std::mem::take(&mut self.overflow);
+                    self.now = waiting.iter().map(|e| self.tick(e)).min().unwrap();
+                    for elem in waiting {
+                        self.place(elem);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn push(&mut self, elem: &'a T) {
+        self.place(elem);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<&'a T> {
+        self.advance();
+        let slot = self.digit(self.now, 0);
+        let current = &mut self.wheels[0][slot];
+        let y = (0..current.len()).reduce(|a, b| if current[b] < current[a] { b } else { a }).map(|at| current.swap_remove(at));
+        if y.is_some() {
+            self.len -= 1;
+            if self.wheels[0][slot].is_empty() {
+                self.occupied[0] &= !(1 << slot);
+            }
+        }
+        y
+    }
+
+    /// Smallest element of the next slot due. Does not turn the wheels, so it may have to scan a coarse slot.
+    pub fn peek(&self) -> Option<&'a T> {
+        let current = &self.wheels[0][self.digit(self.now, 0)];
+        let found = (0..self.wheels.len()).find_map(|w| self.next_slot(w).map(|s| &self.wheels[w][s]));
+        let slot = if current.is_empty() { found.unwrap_or(&self.overflow) } else { current };
+        slot.iter().copied().reduce(|a, b| if b < a { b } else { a })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Empties every wheel without giving back their memory, and starts counting ticks from 0 again.
+    pub fn clear(&mut self) {
+        for slot in self.wheels.iter_mut().flatten() {
+            slot.clear();
+        }
+        self.occupied.iter_mut().for_each(|o| *o = 0);
+        self.overflow.clear();
+        self.now = 0;
+        self.len = 0;
+    }
+
+    /// Removes every element with a key before `time`, a slot at a time.
+    pub fn drain_until(&mut self, time: f64) -> Vec<&'a T> {
+        let mut ret = Vec::new();
+        loop {
+            self.advance();
+            let slot = self.digit(self.now, 0);
+            if self.wheels[0][slot].is_empty() || self.current() >= time {
+                break;
+            }
+            // unless time falls inside this slot the whole slot goes
+            let whole = (self.now + 1) as f64 * self.tickwidth <= time;
+            let before = ret.len();
+            if whole {
+                ret.append(&mut self.take_slot(0, slot));
+            } else {
+                let front = &mut self.wheels[0][slot];
+                ret.extend(front.iter().filter(|e| e.key().0 < time));
+                front.retain(|e| e.key().0 >= time);
+                if front.is_empty() {
+                    self.occupied[0] &= !(1 << slot);
+                }
+            }
+            self.len -= ret.len() - before;
+            if !whole {
+                break;
+            }
+        }
+        ret
+    }
+
+    /// The key where the current tick starts.
+    pub fn current(&self) -> f64 {
+        self.now as f64 * self.tickwidth
+    }
+
+    pub fn bulk_process<F: Fn(&'a T) -> Option<&'a T> + Sync + Send>(&mut self, f: F) where T: Sync {
+        let bucket: Vec<&'a T> = self.bulk_pop().collect();
+        let mapped: Vec<&'a T> = bucket.into_par_iter().filter_map(f).collect();
+        self.bulk_push(mapped.into_par_iter());
+    }
+
+    pub fn bulk_push<I: ParallelIterator<Item = &'a T>>(&mut self, es: I) where T: Sync {
+        let elems: Vec<&'a T> = es.collect();
+        self.len += elems.len();
+        for elem in elems {
+            self.place(elem);
+        }
+    }
+
+    /// Pops every element of the current finest slot, smallest first.
+    pub fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a T> where T: Sync {
+        self.advance();
+        let mut bucket = self.take_slot(0, self.digit(self.now, 0));
+        self.len -= bucket.len();
+        bucket.par_sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        bucket.into_par_iter()
+    }
+}
+
+impl<'a, E: Ord + HasKey> SeqentialPriorityQueue<'a, E> for TimingWheel<&'a E> {
+    fn push(&mut self, e: &'a E) {
+        TimingWheel::push(self, e);
+    }
+    fn pop(&mut self) -> Option<&'a E> {
+        TimingWheel::pop(self)
+    }
+    fn peek(&self) -> Option<&'a E> {
+        TimingWheel::peek(self)
+    }
+    fn is_empty(&self) -> bool {
+        TimingWheel::is_empty(self)
+    }
+    fn len(&self) -> usize {
+        TimingWheel::len(self)
+    }
+    fn clear(&mut self) {
+        TimingWheel::clear(self)
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        TimingWheel::drain_until(self, time)
+    }
+    fn pop_order(&self) -> PopOrder {
+        PopOrder::Bucketed { width: self.tickwidth, sorted: true }
+    }
+}
+
+impl<'a, E: Ord + HasKey + Send + Sync> ParallelPriorityQueue<'a, E> for TimingWheel<&'a E> {
+    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
+        TimingWheel::bulk_process(self, f);
+    }
+    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
+        TimingWheel::bulk_push(self, es);
+    }
+    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
+        TimingWheel::bulk_pop(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let max = 500;
+        let total = max * 5;
+
+        // 2 wheels of 8 slots only reach 64 ticks, so most of these start out in overflow
+        let mut heap1: TimingWheel<&f64> = TimingWheel::new(1.0, 3, 2);
+        assert!(heap1.is_empty());
+
+        let mut rng = rand::thread_rng();
+        let vector: Vec<f64> = (0..total).map(|_| rng.gen_range(0.0..=max as f64)).collect();
+        let mut sortvec = vector.clone();
+        sortvec.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        for (i, y) in vector.iter().enumerate() {
+            heap1.push(y);
+            let min = vector[0..=i].iter().fold(f64::INFINITY, |a, &b| a.min(b));
+            assert_eq!(heap1.peek().unwrap().floor(), min.floor());
+        }
+        assert_eq!(heap1.len(), total);
+        for min in &sortvec {
+            assert_eq!(heap1.peek().unwrap().floor(), min.floor());
+            assert_eq!(heap1.pop().unwrap().floor(), min.floor());
+        }
+        assert!(heap1.is_empty());
+        assert_eq!(heap1.pop(), None);
+    }
+
+    #[test]
+    fn bulk_pop_is_one_slot() {
+        let values: Vec<f64> = (0..4000).map(|i| i as f64 * 0.25).collect();
+        let far = 123456.5;
+        let mut heap1: TimingWheel<&f64> = TimingWheel::new(1.0, 4, 3);
+        heap1.bulk_push(values.par_iter());
+        let mut tick = 0;
+        while tick < 1000 {
+            let mut bucket: Vec<&f64> = heap1.bulk_pop().collect();
+            bucket.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(bucket, values[tick * 4..tick * 4 + 4].iter().collect::<Vec<_>>());
+            assert_eq!(heap1.current(), tick as f64);
+            tick += 1;
+            // something far past the last wheel, and something pushed behind the current tick
+            if tick == 7 {
+                heap1.push(&far);
+                heap1.push(&values[0]);
+                assert_eq!(heap1.pop(), Some(&values[0]));
+            }
+        }
+        assert_eq!(heap1.len(), 1);
+        assert_eq!(heap1.peek(), Some(&far));
+        assert_eq!(heap1.bulk_pop().collect::<Vec<_>>(), vec![&far]);
+        assert!(heap1.is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::sequentialbucketqueue::HasKey;
+
+/// How many keys get looked at when estimating a width from pending keys.
+/// Past a few thousand the estimate stops moving, so there is no point scanning the whole queue.
+#[allow(dead_code)]
+pub const SAMPLE_SIZE: usize = 4096;
+
+/// Fraction of the largest gaps that get thrown away before averaging. The gaps between
+/// global steps are huge compared to the gaps inside a step and would otherwise dominate the mean.
+const GAP_TRIM: f64 = 0.01;
+
+/// Picks a bucket width so that the sampled keys fill the buckets they span with
+/// `occupancy` elements on average. Returns None when there are not enough distinct keys to tell.
+#[allow(dead_code)]
+pub fn width_from_keys<T: HasKey>(keys: &[T], occupancy: f64) -> Option<f64> {
+    if keys.len() < 2 || occupancy <= 0.0 {
+        return None;
+    }
+    let rng = fastrand::Rng::with_seed(keys.len() as u64);
+    let sample = keys.len().min(SAMPLE_SIZE);
+    let mut lo = f64::INFINITY;
+    let mut hi = f64::NEG_INFINITY;
+    for i in 0..sample {
+        // sample without bias towards the front when the queue is bigger than the sample
+        let at = if sample == keys.len() { i } else { rng.usize(..keys.len()) };
+        let k = keys[at].key().0;
+        lo = lo.min(k);
+        hi = hi.max(k);
+    }
+    let span = hi - lo;
+    if span <= 0.0 || !span.is_finite() {
+        return None;
+    }
+    // sample/span keys per unit time, so `occupancy` keys take occupancy*span/sample time
+    Some(occupancy * span / sample as f64)
+}
+
+/// Picks a bucket width from the gaps between consecutive event times of a loaded dump.
+/// The times do not have to be sorted.
+pub fn width_from_gaps(times: &[f64], occupancy: f64) -> Option<f64> {
+    if occupancy <= 0.0 {
+        return None;
+    }
+    let mut sorted: Vec<f64> = times.iter().copied().filter(|t| t.is_finite()).collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mut gaps: Vec<f64> = sorted.windows(2).map(|w| w[1] - w[0]).filter(|g| *g > 0.0).collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort_by(|a, b| a.total_cmp(b));
+    let keep = gaps.len() - (gaps.len() as f64 * GAP_TRIM).floor() as usize;
+    let mean = gaps[..keep].iter().sum::<f64>() / keep as f64;
+    Some(occupancy * mean)
+}
+
+/// How far one retune may move the bucket width, either way. A window of badly overfull buckets
+/// would otherwise ask for a width so narrow that the same horizon needs millions of buckets.
+pub const MAX_RETUNE: f64 = 16.0;
+
+/// The width a queue of `bucketnum` buckets `old` wide actually switches to when asked for `new`,
+/// and how many buckets of it cover the same horizon. Keeps the old width for nonsense like 0 or NaN.
+pub fn retuned(bucketnum: usize, old: f64, new: f64) -> (f64, usize) {
+    let width = if new > 0.0 && new.is_finite() { new.clamp(old / MAX_RETUNE, old * MAX_RETUNE) } else { old };
+    let horizon = bucketnum as f64 * old;
+    // at most MAX_RETUNE times the buckets there were, the +1 catches a horizon that does not divide evenly
+    (width, ((horizon / width).ceil() as usize + 1).min(bucketnum * MAX_RETUNE as usize + 1))
+}
+
+/// Watches how full the popped buckets are and suggests a new width once the running
+/// mean drifts too far from the target. Queues that own one re-bucket themselves.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Retuner {
+    pub target: f64,    // wanted mean number of elements per popped bucket
+    pub tolerance: f64, // how far (as a ratio) the mean may drift before re-bucketing, > 1
+    pub window: usize,  // number of popped buckets averaged before deciding
+    seen: usize,
+    total: usize,
+}
+
+#[allow(dead_code)]
+impl Retuner {
+    pub fn new(target: f64, tolerance: f64, window: usize) -> Self {
+        Retuner { target, tolerance, window: window.max(1), seen: 0, total: 0 }
+    }
+
+    /// Records the size of one popped bucket. Returns the width to switch to when the
+    /// window is full and its mean is outside tolerance, and starts a new window either way.
+    pub fn observe(&mut self, bucket_len: usize, bucketwidth: f64) -> Option<f64> {
+        self.seen += 1;
+        self.total += bucket_len;
+        if self.seen < self.window {
+            return None;
+        }
+        let mean = self.total as f64 / self.seen as f64;
+        self.seen = 0;
+        self.total = 0;
+        if mean <= 0.0 {
+            return None;
+        }
+        let ratio = mean / self.target;
+        if ratio > self.tolerance || ratio < 1.0 / self.tolerance {
+            Some(bucketwidth / ratio)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widths_hit_target() {
+        let times: Vec<f64> = (0..1000).map(|i| i as f64 * 0.5).collect();
+        let w = width_from_gaps(&times, 4.0).unwrap();
+        assert!((w - 2.0).abs() < 1e-9);
+
+        let w = width_from_keys(&times, 4.0).unwrap();
+        assert!((w - 4.0 * 499.5 / 1000.0).abs() < 1e-9);
+
+        assert_eq!(width_from_gaps(&[1.0, 1.0], 4.0), None);
+        assert_eq!(width_from_keys(&[1.0], 4.0), None);
+    }
+
+    #[test]
+    fn retunes_within_bounds() {
+        assert_eq!(retuned(100, 1.0, 0.5), (0.5, 201));
+        assert_eq!(retuned(100, 1.0, 1e-9), (1.0 / MAX_RETUNE, 1601));
+        assert_eq!(retuned(100, 1.0, 1e9), (MAX_RETUNE, 8));
+        assert_eq!(retuned(100, 1.0, 0.0), (1.0, 101));
+        assert_eq!(retuned(100, 1.0, f64::NAN), (1.0, 101));
+    }
+
+    #[test]
+    fn retuner_waits_for_window() {
+        let mut r = Retuner::new(10.0, 2.0, 3);
+        assert_eq!(r.observe(40, 1.0), None);
+        assert_eq!(r.observe(40, 1.0), None);
+        assert_eq!(r.observe(40, 1.0), Some(0.25));
+        for _ in 0..3 {
+            assert_eq!(r.observe(12, 1.0), None);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::Mutex;
+use rayon::prelude::*;
+
+use crate::{sequentialbucketqueue::HasKey, ParallelPriorityQueue, PopOrder, SeqentialPriorityQueue};
+
+/// Identity of an element apart from its key, e.g. the particle pair an event belongs to.
+pub trait HasId {
+    type Id: Hash + Eq + Copy + Send + Sync;
+    fn id(&self) -> Self::Id;
+}
+
+/// What happens when an element is pushed while another one with the same id is still queued.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    KeepEarliest, // the one that orders first stays, the other is dropped
+    Replace,      // the newest push wins
+}
+
+// spread over a few locks so bulk_push from many threads does not all wait on one map
+const SHARDS: usize = 16;
+
+/// Wraps a queue so that at most one element per id is queued at a time. Losers of a duplicate are
+/// not dug out of the inner queue, they are just forgotten here and skipped when they come out of it,
+/// so this works on top of any queue, including ParBqueue's concurrent bulk_push.
+pub struct UniqueQueue<'a, E: HasId, PQ> {
+    inner: PQ,
+    policy: DuplicatePolicy,
+    hasher: RandomState,
+    live: Vec<Mutex<HashMap<E::Id, &'a E>>>,
+    spare: Vec<&'a E>, // reused by bulk_process for the popped bucket
+}
+
+#[allow(dead_code)]
+impl<'a, E: HasId + HasKey, PQ> UniqueQueue<'a, E, PQ> {
+    pub fn new(inner: PQ, policy: DuplicatePolicy) -> Self {
+        UniqueQueue {
+            inner,
+            policy,
+            hasher: RandomState::new(),
+            live: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            spare: Vec::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &PQ {
+        &self.inner
+    }
+}
+
+fn shard<'m, 'a, E: HasId>(live: &'m [Mutex<HashMap<E::Id, &'a E>>], hasher: &RandomState, id: &E::Id) -> &'m Mutex<HashMap<E::Id, &'a E>> {
+    &live[hasher.hash_one(id) as usize % live.len()]
+}
+
+// decides whether e goes into the inner queue, and if so makes it the live element for its id. Goes by
+// the full order and not just the key, so with concurrent pushes the same one wins whichever thread
+// gets here first.
+fn admit<'a, E: Ord + HasId>(live: &[Mutex<HashMap<E::Id, &'a E>>], hasher: &RandomState, policy: DuplicatePolicy, e: &'a E) -> bool {
+    let id = e.id();
+    let mut map = shard(live, hasher, &id).lock().unwrap();
+    match map.get(&id) {
+        Some(old) if policy == DuplicatePolicy::KeepEarliest && *old <= e => false,
+        _ => {
+            map.insert(id, e);
+            true
+        }
+    }
+}
+
+// true when e is the live element for its id, which it stops being from here on
+fn take<'a, E: HasId>(live: &[Mutex<HashMap<E::Id, &'a E>>], hasher: &RandomState, e: &'a E) -> bool {
+    let id = e.id();
+    let mut map = shard(live, hasher, &id).lock().unwrap();
+    match map.get(&id) {
+        Some(current) if std::ptr::eq(*current, e) => {
+            map.remove(&id);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn is_live<'a, E: HasId>(live: &[Mutex<HashMap<E::Id, &'a E>>], hasher: &RandomState, e: &'a E) -> bool {
+    let id = e.id();
+    let map = shard(live, hasher, &id).lock().unwrap();
+    map.get(&id).is_some_and(|current| std::ptr::eq(*current, e))
+}
+
+impl<'a, E: Ord + HasId + HasKey, PQ: SeqentialPriorityQueue<'a, E>> UniqueQueue<'a, E, PQ> {
+    /// Gets rid of losers sitting at the front of the inner queue so peek only ever shows a live element.
+    /// Live elements popped on the way (a bucketed queue may pop another element of the same bucket
+    /// before the one it peeks) go straight back in.
+    fn settle(&mut self) {
+        while let Some(front) = self.inner.peek() {
+            if is_live(&self.live, &self.hasher, front) {
+                return;
+            }
+            let mut back = Vec::new();
+            while let Some(e) = self.inner.pop() {
+                if std::ptr::eq(e, front) {
+                    break;
+                }
+                if is_live(&self.live, &self.hasher, e) {
+                    back.push(e);
+                }
+            }
+            for e in back {
+                self.inner.push(e);
+            }
+        }
+    }
+}
+
+impl<'a, E: Ord + HasId + HasKey, PQ: SeqentialPriorityQueue<'a, E>> SeqentialPriorityQueue<'a, E> for UniqueQueue<'a, E, PQ> {
+    fn push(&mut self, e: &'a E) {
+        if admit(&self.live, &self.hasher, self.policy, e) {
+            self.inner.push(e);
+            self.settle();
+        }
+    }
+    fn pop(&mut self) -> Option<&'a E> {
+        while let Some(e) = self.inner.pop() {
+            if take(&self.live, &self.hasher, e) {
+                self.settle();
+                return Some(e);
+            }
+        }
+        None
+    }
+    fn peek(&self) -> Option<&'a E> {
+        self.inner.peek()
+    }
+    fn is_empty(&self) -> bool {
+        self.live.iter().all(|s| s.lock().unwrap().is_empty())
+    }
+    /// Number of elements that will still come out, duplicates that lost do not count.
+    fn len(&self) -> usize {
+        self.live.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+    fn clear(&mut self) {
+        self.inner.clear();
+        for shard in self.live.iter_mut() {
+            shard.get_mut().unwrap().clear();
+        }
+    }
+    fn drain_until(&mut self, time: f64) -> Vec<&'a E> {
+        let mut ret = self.inner.drain_until(time);
+        ret.retain(|e| take(&self.live, &self.hasher, e));
+        self.settle();
+        ret
+    }
+    fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional)
+    }
+    fn pop_order(&self) -> PopOrder {
+        self.inner.pop_order()
+    }
+}
+
+impl<'a, E: Ord + HasId + HasKey + Send + Sync + 'a, PQ: ParallelPriorityQueue<'a, E>> ParallelPriorityQueue<'a, E> for UniqueQueue<'a, E, PQ> {
+    fn bulk_process<F: Fn(&'a E) -> Option<&'a E> + Sync + Send>(&mut self, f: F) {
+        let mut bucket = std::mem::take(&mut self.spare);
+        self.bulk_pop_into(&mut bucket);
+        self.bulk_push(bucket.par_iter().filter_map(|e| f(e)));
+        self.spare = bucket;
+    }
+    fn bulk_push<I: ParallelIterator<Item = &'a E>>(&mut self, es: I) {
+        let (live, hasher, policy) = (&self.live, &self.hasher, self.policy);
+        self.inner.bulk_push(es.filter(|e| admit(live, hasher, policy, e)));
+        self.settle();
+    }
+    fn bulk_pop(&mut self) -> impl ParallelIterator<Item = &'a E> {
+        let (live, hasher) = (&self.live, &self.hasher);
+        let bucket: Vec<&'a E> = self.inner.bulk_pop().filter(|e| take(live, hasher, e)).collect();
+        self.settle();
+        bucket.into_par_iter()
+    }
+    fn bulk_pop_into(&mut self, buf: &mut Vec<&'a E>) {
+        self.inner.bulk_pop_into(buf);
+        buf.retain(|e| take(&self.live, &self.hasher, e));
+        self.settle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BinaryHeap;
+    use ordered_float::OrderedFloat;
+
+    use crate::parallelbucketqueue::ParBqueue;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Ev(OrderedFloat<f64>, u32);
+
+    impl HasKey for Ev {
+        fn key(&self) -> OrderedFloat<f64> {
+            self.0
+        }
+    }
+
+    impl HasId for Ev {
+        type Id = u32;
+        fn id(&self) -> u32 {
+            self.1
+        }
+    }
+
+    fn ev(t: f64, id: u32) -> Ev {
+        Ev(OrderedFloat(t), id)
+    }
+
+    #[test]
+    fn keeps_one_per_id() {
+        let evs = [ev(3.0, 1), ev(1.0, 1), ev(2.0, 2), ev(5.0, 1), ev(4.0, 2)];
+
+        let mut q = UniqueQueue::new(BinaryHeap::new(), DuplicatePolicy::KeepEarliest);
+        for e in &evs {
+            q.push(e);
+        }
+        assert_eq!(q.len(), 2);
+        let mut popped = Vec::new();
+        while let Some(e) = q.pop() {
+            popped.push(e);
+        }
+        popped.sort();
+        assert_eq!(popped, vec![&evs[1], &evs[2]]);
+        assert!(q.is_empty());
+
+        // once popped the id is free again
+        q.push(&evs[3]);
+        assert_eq!(q.peek(), Some(&evs[3]));
+        assert_eq!(q.pop(), Some(&evs[3]));
+
+        let mut q = UniqueQueue::new(BinaryHeap::new(), DuplicatePolicy::Replace);
+        for e in &evs {
+            q.push(e);
+        }
+        let mut popped = Vec::new();
+        while let Some(e) = q.pop() {
+            popped.push(e);
+        }
+        popped.sort();
+        assert_eq!(popped, vec![&evs[4], &evs[3]]);
+    }
+
+    #[test]
+    fn concurrent_bulk_push() {
+        // every id shows up 10 times, the earliest one is at id/10
+        let evs: Vec<Ev> = (0..10_000).map(|i| ev((i % 1000) as f64 / 10.0 + (i / 1000) as f64, (i % 1000) as u32)).collect();
+        let mut q = UniqueQueue::new(ParBqueue::new(120, 1.0), DuplicatePolicy::KeepEarliest);
+        q.bulk_push(evs.par_iter());
+        assert_eq!(q.len(), 1000);
+        let mut popped = Vec::new();
+        while !q.is_empty() {
+            popped.extend(q.bulk_pop().collect::<Vec<_>>());
+        }
+        popped.sort();
+        assert_eq!(popped, evs[..1000].iter().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Write};
+
+use crate::bindump::{self, DumpFile};
+use crate::csvreader::{self, Rec, Schema, COLUMNS};
+use crate::particle::{Pair, Particle};
+use crate::rawreader;
+use crate::stream;
+
+/// What a row can get wrong, as the error list calls it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    Parse,      // the row does not read as an event at all
+    NonFinite,  // an infinite or NaN value, masses may be NaN as that means unknown
+    Radius,     // a radius that is zero or less
+    SelfPair,   // p1 == p2
+    Duplicate,  // the pair already had an event at that time
+    Backwards,  // earlier than the pair's event before it
+    Position,   // the particles are not where the event type says they should be
+}
+
+pub const CHECKS: [Check; 7] = [Check::Parse, Check::NonFinite, Check::Radius, Check::SelfPair, Check::Duplicate, Check::Backwards, Check::Position];
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Check::Parse => "parse",
+            Check::NonFinite => "non_finite",
+            Check::Radius => "radius",
+            Check::SelfPair => "self_pair",
+            Check::Duplicate => "duplicate",
+            Check::Backwards => "backwards",
+            Check::Position => "position",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One problem with one row. Binary dumps have no lines, their rows are counted from 1 instead.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Issue {
+    pub line: u64,
+    pub check: Check,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.check, self.message)
+    }
+}
+
+/// A row as the validator gets it. `kind` is the event type, for dumps that have a column for it.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub line: u64,
+    pub rec: Result<Rec, String>,
+    pub kind: Option<String>,
+}
+
+// names the event type column goes by, after csvreader's normalising
+const KIND_COLUMNS: [&str; 3] = ["eventtype", "type", "kind"];
+
+// event types where the particles touch, and where they have run into each other
+const TOUCHING: [&str; 4] = ["collision", "collide", "bounce", "contact"];
+const OVERLAPPING: [&str; 2] = ["merge", "merger"];
+
+// how far off touching, relative to the sum of the radii, still counts as touching. The positions
+// in a dump went through an integrator and a float format, they are never exactly in contact.
+const TOLERANCE: f64 = 1e-3;
+
+/// Checks rows one at a time, in file order, remembering only the last event of every pair.
+#[derive(Debug, Default)]
+pub struct Validator {
+    issues: Vec<Issue>,
+    rows: u64,
+    stateless: u64,
+    positioned: u64,
+    last: HashMap<Pair, (u64, Rec)>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn issue(&mut self, line: u64, check: Check, message: String) {
+        self.issues.push(Issue { line, check, message });
+    }
+
+    pub fn check(&mut self, row: Row) {
+        self.rows += 1;
+        let line = row.line;
+        let rec = match row.rec {
+            Ok(rec) => rec,
+            Err(e) => return self.issue(line, Check::Parse, e),
+        };
+
+        let values = rec.values();
+        for (name, v) in COLUMNS[2..].iter().zip(values) {
+            let unknown_mass = matches!(*name, "p1m" | "p2m") && v.is_nan();
+            if !v.is_finite() && !unknown_mass {
+                self.issue(line, Check::NonFinite, format!("`{}` is {}", name, v));
+            }
+        }
+        if rec.p1 == rec.p2 {
+            self.issue(line, Check::SelfPair, format!("particle {} collides with itself", rec.p1.0));
+        }
+
+        // events with just the pair and the time have no particles to look at
+        if values[1..15].iter().all(|&v| v == 0.0) {
+            self.stateless += 1;
+        } else {
+            for (name, r) in [("p1r", rec.p1r), ("p2r", rec.p2r)] {
+                if r <= 0.0 {
+                    self.issue(line, Check::Radius, format!("`{}` is {}", name, r));
+                }
+            }
+            if let Some(kind) = row.kind {
+                self.position(line, &rec, &kind);
+            }
+        }
+
+        match self.last.insert(rec.pair(), (line, rec)) {
+            Some((before, prev)) if rec.time < prev.time => {
+                self.issue(line, Check::Backwards, format!("pair {} is at {} here but was at {} on line {}", rec.pair(), rec.time, prev.time, before));
+            }
+            Some((before, prev)) if rec.time == prev.time => {
+                let message = if bytemuck::bytes_of(&rec) == bytemuck::bytes_of(&prev) {
+                    format!("same row as line {}", before)
+                } else {
+                    format!("pair {} already has an event at {} on line {}", rec.pair(), rec.time, before)
+                };
+                self.issue(line, Check::Duplicate, message);
+            }
+            _ => {}
+        }
+    }
+
+    // holds the particles to what the event type says about them, types it does not know pass
+    fn position(&mut self, line: u64, rec: &Rec, kind: &str) {
+        let lower = kind.to_lowercase();
+        let touching = TOUCHING.contains(&lower.as_str());
+        if !touching && !OVERLAPPING.contains(&lower.as_str()) {
+            return;
+        }
+        let (p1, p2) = (rec.p1(), rec.p2());
+        if !(p1.r > 0.0 && p2.r > 0.0 && p1.distance(&p2).is_finite()) {
+            return; // radius and non_finite already say what is wrong
+        }
+        self.positioned += 1;
+        // with the radii grown by the tolerance, particles that are still apart are too far apart
+        let grown = |p: Particle| Particle { r: p.r * (1.0 + TOLERANCE), ..p };
+        if grown(p1).overlap_fraction(&grown(p2)).is_none() {
+            let gap = p1.distance(&p2) - p1.r - p2.r;
+            self.issue(line, Check::Position, format!("particles are {:e} apart at a `{}` event", gap, kind));
+        } else if let Some(overlap) = p1.overlap_fraction(&p2).filter(|&o| touching && o > TOLERANCE) {
+            self.issue(line, Check::Position, format!("particles overlap by {:.3}% of their radii at a `{}` event", overlap * 100.0, kind));
+        }
+    }
+
+    pub fn finish(self) -> Report {
+        Report { rows: self.rows, pairs: self.last.len(), stateless: self.stateless, positioned: self.positioned, issues: self.issues }
+    }
+}
+
+/// What validating a dump found. Displays as a summary, `{:#}` adds a line per issue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub rows: u64,
+    pub pairs: usize,
+    pub stateless: u64,  // rows without particle state, so no radius or position checks
+    pub positioned: u64, // rows whose positions were held to their event type
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn count(&self, check: Check) -> usize {
+        self.issues.iter().filter(|i| i.check == check).count()
+    }
+
+    /// The issues as csv, `line,check,message`, for whatever reads them next.
+    pub fn write_csv<W: Write>(&self, w: W) -> Result<(), Box<dyn Error>> {
+        let mut wtr = csv::Writer::from_writer(w);
+        if self.issues.is_empty() {
+            wtr.write_record(["line", "check", "message"])?;
+        }
+        for issue in &self.issues {
+            wtr.serialize(issue)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} rows of {} pairs, {} issues", self.rows, self.pairs, self.issues.len())?;
+        for check in CHECKS {
+            write!(f, "\n  {}: {}", check, self.count(check))?;
+        }
+        if self.stateless > 0 {
+            write!(f, "\n  {} rows have no particle state, their radii and positions were not checked", self.stateless)?;
+        }
+        write!(f, "\n  positions checked on {} rows, the others have no event type that says where the particles are", self.positioned)?;
+        if f.alternate() {
+            for issue in &self.issues {
+                write!(f, "\n{}", issue)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the dump at `path` the way main would and checks every row of it. A row that does not
+/// parse is reported like any other problem and reading carries on, only a dump that can not be
+/// read at all is an error.
+pub fn validate(path: &str) -> Result<Report, Box<dyn Error>> {
+    let path = path.replace("\"","").replace("\\","/");
+    let path = path.trim();
+    let mut v = Validator::new();
+    if bindump::is_dump(path) {
+        let dump = DumpFile::open(path)?;
+        for (i, rec) in dump.records().iter().enumerate() {
+            v.check(Row { line: i as u64 + 1, rec: Ok(*rec), kind: None });
+        }
+    } else if stream::is_csv(path) {
+        let mut rdr = csv::ReaderBuilder::new().flexible(true).comment(Some(b'#')).from_path(path)?;
+        let schema = Schema::new(rdr.byte_headers()?)?;
+        let kind = schema.extra_column(&KIND_COLUMNS);
+        for record in rdr.byte_records() {
+            let row = match record {
+                Ok(record) => Row {
+                    line: csvreader::line_of(&record),
+                    rec: schema.rec(&record).map_err(|e| e.to_string()),
+                    kind: kind.map(|i| schema.extra(&record, i)),
+                },
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                Err(e) => Row { line: e.position().map_or(0, |p| p.line()), rec: Err(e.to_string()), kind: None },
+            };
+            v.check(row);
+        }
+    } else {
+        for event in rawreader::numbered_events(BufReader::new(File::open(path)?)) {
+            let (line, rec) = event?;
+            v.check(Row { line, rec: rec.map_err(|e| e.to_string()), kind: None });
+        }
+    }
+    Ok(v.finish())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::particle::ParticleIndex;
+
+    use super::*;
+
+    fn path(name: &str) -> String {
+        std::env::temp_dir().join(format!("queue_test_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn found(report: &Report) -> Vec<(u64, Check)> {
+        report.issues.iter().map(|i| (i.line, i.check)).collect()
+    }
+
+    #[test]
+    fn finds_every_kind_of_problem() {
+        let csv = path("bad.csv");
+        std::fs::write(&csv, "\
+p1,p2,time,p1x,p1y,p1z,p1vx,p1vy,p1vz,p1r,p2x,p2y,p2z,p2vx,p2vy,p2vz,p2r,event type
+1,2,0.5,0,0,0,0,0,0,0.5,1,0,0,0,0,0,0.5,bounce
+1,2,0.5,0,0,0,0,0,0,0.5,1,0,0,0,0,0,0.5,bounce
+3,3,0.6,0,0,0,0,0,0,0.5,1,0,0,0,0,0,0.5,bounce
+2,1,0.4,0,0,0,0,0,0,0.5,1,0,0,0,0,0,0.5,bounce
+4,5,0.7,0,0,0,inf,0,0,0.5,1,0,0,0,0,0,0,miss
+4,6,x,0,0,0,0,0,0,0.5,1,0,0,0,0,0,0.5,bounce
+4,7,0.8,0,0,0,0,0,0,0.5,3,0,0,0,0,0,0.5,bounce
+4,8,0.9,0,0,0,0,0,0,0.5,0.5,0,0,0,0,0,0.5,bounce
+4,9,1.0,0,0,0,0,0,0,0.5,0.5,0,0,0,0,0,0.5,merge
+4,9,1.1,0,0,0,0,0,0,0.5,3,0,0,0,0,0,0.5,merge
+4,10,1.2,0,0,0,0,0,0,0.5,1.0000001,0,0,0,0,0,0.5,Collision
+4,11
+").unwrap();
+        let report = validate(&csv).unwrap();
+        assert_eq!(found(&report), [
+            (3, Check::Duplicate),
+            (4, Check::SelfPair),
+            (5, Check::Backwards),
+            (6, Check::NonFinite),
+            (6, Check::Radius),
+            (7, Check::Parse),
+            (8, Check::Position),
+            (9, Check::Position),
+            (11, Check::Position),
+            (13, Check::Parse),
+        ]);
+        assert!(report.issues[0].message.contains("same row as line 2"), "{}", report.issues[0]);
+        assert!(report.issues[2].message.contains("on line 3"), "{}", report.issues[2]);
+        assert!(report.issues[3].message.contains("`p1vx` is inf"), "{}", report.issues[3]);
+        assert!(report.issues[7].message.contains("overlap by 50.000%"), "{}", report.issues[7]);
+        assert_eq!((report.rows, report.positioned, report.count(Check::Position)), (12, 9, 3));
+        assert!(!report.is_clean());
+
+        let mut out = Vec::new();
+        report.write_csv(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("line,check,message\n3,duplicate,same row as line 2\n"), "{}", text);
+        assert_eq!(text.lines().count(), 11);
+        assert_eq!(format!("{:#}", report).lines().count(), 9 + report.issues.len());
+    }
+
+    #[test]
+    fn same_checks_for_every_format() {
+        let p = Particle::new(crate::vectors::Vector::new(0.0, 0.0, 0.0), crate::vectors::Vector::new(1.0, 0.0, 0.0), 0.5, 1.0);
+        let mut recs = vec![
+            Rec::new(ParticleIndex(1), ParticleIndex(2), &p, &p, 0.5),
+            Rec::new(ParticleIndex(2), ParticleIndex(1), &p, &p, 0.25),
+            Rec::new(ParticleIndex(3), ParticleIndex(3), &p, &p, 0.75),
+        ];
+        recs[2].p2r = -1.0;
+        let base = path("formats");
+        csvreader::write_csv(File::create(format!("{}.csv", base)).unwrap(), &recs).unwrap();
+        rawreader::write_events(&mut File::create(format!("{}.dump", base)).unwrap(), &recs).unwrap();
+        bindump::write_dump(&mut File::create(format!("{}.qtev", base)).unwrap(), &recs, &crate::metadata::Metadata::new()).unwrap();
+
+        let expected = [(Check::Backwards, 2), (Check::SelfPair, 3), (Check::Radius, 3)];
+        for (ext, first) in [("csv", 2), ("dump", 1), ("qtev", 1)] {
+            let report = validate(&format!("{}.{}", base, ext)).unwrap();
+            let at: Vec<(u64, Check)> = expected.iter().map(|&(c, row)| (row + first - 1, c)).collect();
+            assert_eq!(found(&report), at, "{}", ext);
+            assert_eq!((report.rows, report.pairs, report.positioned), (3, 2, 0), "{}", ext);
+        }
+    }
+
+    #[test]
+    fn keeps_reading_after_a_bad_line() {
+        let raw = path("bad.dump");
+        std::fs::write(&raw, "started\nEvent: 1 2 0.5\nEvent: 1 2.5 0.5\nEvent: 1 2\nEvent: 1 2 0.25\n").unwrap();
+        let report = validate(&raw).unwrap();
+        assert_eq!(found(&report), [(3, Check::Parse), (4, Check::Parse), (5, Check::Backwards)]);
+        assert!(report.issues[0].message.contains("not a whole number"));
+        assert_eq!(report.stateless, 2);
+
+        let clean = path("clean.dump");
+        std::fs::write(&clean, "Event: 1 2 0.5\nEvent: 2 3 0.5\n").unwrap();
+        let report = validate(&clean).unwrap();
+        assert!(report.is_clean());
+        let mut out = Vec::new();
+        report.write_csv(&mut out).unwrap();
+        assert_eq!(out, b"line,check,message\n");
+    }
+}